@@ -0,0 +1,336 @@
+use crate::*;
+
+use hashbrown::HashMap;
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    Body, HeaderMap,
+};
+use rand::Rng;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+// Picks the target of a route for each request
+// A std mutex, because connections are given back when they are dropped and that can't wait
+#[derive(Clone, Default)]
+pub struct Balancer {
+    state: Arc<Mutex<BalancerState>>,
+}
+
+#[derive(Default)]
+struct BalancerState {
+    // Where round robin continues for each route
+    next: HashMap<i64, usize>,
+    // Open requests and upgraded connections for each upstream, over all routes
+    active: HashMap<String, usize>,
+}
+
+impl Balancer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Pick one of the targets with the strategy of the route
+    pub fn pick(
+        &self,
+        proxy: &Proxy,
+        targets: &[Target],
+        ip: IpAddr,
+        user: &str,
+    ) -> Option<Target> {
+        if targets.len() <= 1 {
+            return targets.first().cloned();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let target = match proxy.balance {
+            Balance::RoundRobin => {
+                let start = state.rotate(proxy.id);
+                &targets[start % targets.len()]
+            }
+            // Rotate first, so targets with as few connections as the others still take turns
+            Balance::LeastConnections => {
+                let start = state.rotate(proxy.id);
+                (0..targets.len())
+                    .map(|i| &targets[(start + i) % targets.len()])
+                    .min_by_key(|target| state.active(target))?
+            }
+            Balance::PowerOfTwo => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0..targets.len());
+                let second = (first + rng.gen_range(1..targets.len())) % targets.len();
+                match state.active(&targets[second]) < state.active(&targets[first]) {
+                    true => &targets[second],
+                    false => &targets[first],
+                }
+            }
+            Balance::IpHash => rendezvous(targets, &ip),
+            Balance::UserHash => rendezvous(targets, user),
+        };
+
+        Some(target.clone())
+    }
+
+    // Count a request to the target until the returned connection is dropped
+    pub fn connect(&self, target: &Target) -> ActiveConnection {
+        let authority = target.authority();
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .active
+            .entry(authority.clone())
+            .or_default() += 1;
+
+        ActiveConnection {
+            balancer: self.clone(),
+            authority,
+        }
+    }
+}
+
+impl BalancerState {
+    fn rotate(&mut self, proxy_id: i64) -> usize {
+        let next = self.next.entry(proxy_id).or_default();
+        let current = *next;
+        *next = next.wrapping_add(1);
+
+        current
+    }
+
+    fn active(&self, target: &Target) -> usize {
+        self.active
+            .get(&target.authority())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+// Consistent hashing: every key sticks to the target it scores highest with,
+// so adding or removing a target only moves the keys of that target
+fn rendezvous<'a, K: Hash + ?Sized>(targets: &'a [Target], key: &K) -> &'a Target {
+    targets
+        .iter()
+        .max_by_key(|target| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            target.authority().hash(&mut hasher);
+            hasher.finish()
+        })
+        .unwrap_or(&targets[0])
+}
+
+// A request or upgraded connection to an upstream that counts for least connections
+pub struct ActiveConnection {
+    balancer: Balancer,
+    authority: String,
+}
+
+impl ActiveConnection {
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    // Keep counting the connection until the body has been sent to the client
+    pub fn hold_until_sent(self, body: Body) -> HeldBody {
+        HeldBody {
+            body,
+            _connection: self,
+        }
+    }
+}
+
+// A response body that gives its connection back when it is dropped
+// Everything is passed on as it is, so the length and trailers of the upstream response stay
+pub struct HeldBody {
+    body: Body,
+    _connection: ActiveConnection,
+}
+
+impl HttpBody for HeldBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut state = self.balancer.state.lock().unwrap();
+        if let Some(count) = state.active.get_mut(&self.authority) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                state.active.remove(&self.authority);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(port: u16) -> Target {
+        Target {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    fn proxy(balance: Balance) -> Proxy {
+        Proxy {
+            id: 1,
+            name: "app.local".to_string(),
+            targets: (3000..3003).map(target).collect(),
+            balance,
+            is_enabled: true,
+            ..Proxy::default()
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 1, last])
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let balancer = Balancer::new();
+        let proxy = proxy(Balance::RoundRobin);
+
+        let picked = (0..6)
+            .map(|_| balancer.pick(&proxy, &proxy.targets, ip(1), "stan"))
+            .map(|target| target.unwrap().port)
+            .collect::<Vec<_>>();
+        assert_eq!(picked, [3000, 3001, 3002, 3000, 3001, 3002]);
+    }
+
+    #[test]
+    fn least_connections_counts_until_dropped() {
+        let balancer = Balancer::new();
+        let proxy = proxy(Balance::LeastConnections);
+
+        // Every target gets a connection, then the one of 3001 ends
+        let connections = proxy
+            .targets
+            .iter()
+            .map(|target| balancer.connect(target))
+            .collect::<Vec<_>>();
+        let busy = balancer.connect(&target(3000));
+        drop(connections);
+        let _open = [
+            balancer.connect(&target(3000)),
+            balancer.connect(&target(3002)),
+        ];
+
+        for _ in 0..3 {
+            let picked = balancer.pick(&proxy, &proxy.targets, ip(1), "stan");
+            assert_eq!(picked, Some(target(3001)));
+        }
+
+        drop(busy);
+        assert_eq!(balancer.state.lock().unwrap().active(&target(3000)), 1);
+    }
+
+    #[test]
+    fn power_of_two_avoids_the_busiest_target() {
+        let balancer = Balancer::new();
+        let proxy = proxy(Balance::PowerOfTwo);
+        let _busy = balancer.connect(&target(3001));
+
+        // Whichever two are compared, the busy one loses
+        for _ in 0..100 {
+            let picked = balancer.pick(&proxy, &proxy.targets, ip(1), "stan");
+            assert_ne!(picked, Some(target(3001)));
+        }
+    }
+
+    #[test]
+    fn rendezvous_only_moves_the_keys_of_a_removed_target() {
+        let balancer = Balancer::new();
+        let mut proxy = proxy(Balance::IpHash);
+        let before = (0..=255)
+            .map(|last| balancer.pick(&proxy, &proxy.targets, ip(last), "stan"))
+            .collect::<Vec<_>>();
+        assert!(proxy
+            .targets
+            .iter()
+            .all(|t| before.contains(&Some(t.clone()))));
+
+        let removed = proxy.targets.remove(1);
+        for (last, before) in (0..=255).zip(before) {
+            let after = balancer.pick(&proxy, &proxy.targets, ip(last), "stan");
+            match before == Some(removed.clone()) {
+                true => assert!(after.is_some_and(|after| after != removed)),
+                false => assert_eq!(after, before),
+            }
+        }
+
+        // The same user always lands on the same target
+        let proxy = self::proxy(Balance::UserHash);
+        let first = balancer.pick(&proxy, &proxy.targets, ip(1), "stan");
+        for last in 0..10 {
+            assert_eq!(
+                balancer.pick(&proxy, &proxy.targets, ip(last), "stan"),
+                first
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_unhealthy_targets() {
+        let balancer = Balancer::new();
+        let health = Health::new(&Config::for_tests(&[]));
+        for balance in Balance::ALL {
+            let proxy = proxy(balance);
+            for _ in 0..proxy.health.fails {
+                health
+                    .failed(&proxy, &target(3001), "refused".to_string())
+                    .await;
+            }
+
+            let targets = health.available(&proxy).await;
+            for last in 0..20 {
+                let picked = balancer.pick(&proxy, &targets, ip(last), "stan");
+                assert!(picked.is_some_and(|picked| picked != target(3001)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn held_bodies_keep_their_length_until_sent() {
+        let balancer = Balancer::new();
+        let connection = balancer.connect(&target(3000));
+        let body = connection.hold_until_sent(Body::from("Hello, world!"));
+        assert_eq!(body.size_hint().exact(), Some(13));
+        assert_eq!(balancer.state.lock().unwrap().active(&target(3000)), 1);
+
+        let data = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(data, "Hello, world!");
+        assert_eq!(balancer.state.lock().unwrap().active(&target(3000)), 0);
+    }
+}
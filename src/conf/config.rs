@@ -22,6 +22,24 @@ impl Config {
         }
     }
 
+    // A config with the default values, and the given values on top
+    #[cfg(test)]
+    pub fn for_tests(values: &[(ConfigOptions, &str)]) -> Self {
+        use strum::IntoEnumIterator;
+
+        let mut conf = Self::new();
+        for key in ConfigOptions::iter() {
+            if let Some(default) = key.default_value() {
+                conf.set(key, default.to_string());
+            }
+        }
+        for (key, value) in values {
+            conf.set(key.clone(), value.to_string());
+        }
+
+        conf
+    }
+
     pub(super) fn set(&mut self, key: ConfigOptions, value: String) {
         self.store.insert(key, value);
     }
@@ -11,12 +11,18 @@ pub fn conf() -> Result<Config, Error> {
     // Iterate over all config options
     let mut conf = Config::new();
     for key in ConfigOptions::iter() {
-        // Get the environment variable
-        let value = var(PREFIX.to_owned() + &key.to_string())
-            .map_err(|_| Error::MissingEnvVar(key.to_string()))?;
+        // Get the environment variable, falling back to the default value
+        let value = match (
+            var(PREFIX.to_owned() + &key.to_string()),
+            key.default_value(),
+        ) {
+            (Ok(value), _) => value,
+            (Err(_), Some(default)) => default.to_string(),
+            (Err(_), None) => return Err(Error::MissingEnvVar(key.to_string())),
+        };
 
-        // Check if the value is empty
-        if value.is_empty() {
+        // Check if the value is empty (allowed when the option is empty by default)
+        if value.is_empty() && key.default_value() != Some("") {
             return Err(Error::EmptyEnvVar(key.to_string()));
         }
 
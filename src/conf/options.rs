@@ -3,8 +3,23 @@ use strum_macros::EnumIter;
 // All different config options
 #[derive(Hash, Eq, PartialEq, Debug, Clone, EnumIter)]
 pub enum ConfigOptions {
+    SessionReapInterval,
+    SessionMaxLifetime,
     SessionExpireTime,
     SpecialRoute,
+    LoginMaxLockoutTime,
+    LoginLockoutTime,
+    LoginMaxAttemptsIp,
+    LoginMaxAttempts,
+    TrustedProxies,
+    UpgradeIdleTimeout,
+    HealthEjectTime,
+    RemoteGroupsHeader,
+    RemoteAdminHeader,
+    RemoteUserHeader,
+    CookieSameSite,
+    CookieSecure,
+    CookieDomain,
     CookieName,
     StaticDir,
     DbFile,
@@ -13,16 +28,57 @@ pub enum ConfigOptions {
 }
 
 // Convert the config options to a string
-impl ToString for ConfigOptions {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ConfigOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConfigOptions::SessionReapInterval => "SESSION_REAP_INTERVAL",
+            ConfigOptions::SessionMaxLifetime => "SESSION_MAX_LIFETIME",
+            ConfigOptions::SessionExpireTime => "SESSION_EXPIRE_TIME",
+            ConfigOptions::SpecialRoute => "SPECIAL_ROUTE",
+            ConfigOptions::LoginMaxLockoutTime => "LOGIN_MAX_LOCKOUT_TIME",
+            ConfigOptions::LoginLockoutTime => "LOGIN_LOCKOUT_TIME",
+            ConfigOptions::LoginMaxAttemptsIp => "LOGIN_MAX_ATTEMPTS_IP",
+            ConfigOptions::LoginMaxAttempts => "LOGIN_MAX_ATTEMPTS",
+            ConfigOptions::TrustedProxies => "TRUSTED_PROXIES",
+            ConfigOptions::UpgradeIdleTimeout => "UPGRADE_IDLE_TIMEOUT",
+            ConfigOptions::HealthEjectTime => "HEALTH_EJECT_TIME",
+            ConfigOptions::RemoteGroupsHeader => "REMOTE_GROUPS_HEADER",
+            ConfigOptions::RemoteAdminHeader => "REMOTE_ADMIN_HEADER",
+            ConfigOptions::RemoteUserHeader => "REMOTE_USER_HEADER",
+            ConfigOptions::CookieSameSite => "COOKIE_SAMESITE",
+            ConfigOptions::CookieSecure => "COOKIE_SECURE",
+            ConfigOptions::CookieDomain => "COOKIE_DOMAIN",
+            ConfigOptions::CookieName => "COOKIE_NAME",
+            ConfigOptions::StaticDir => "STATIC_DIR",
+            ConfigOptions::DbFile => "DB_FILE",
+            ConfigOptions::Port => "PORT",
+            ConfigOptions::Ip => "IP",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl ConfigOptions {
+    // Value used when the option is not set, options without a default are required
+    pub fn default_value(&self) -> Option<&'static str> {
         match self {
-            ConfigOptions::SessionExpireTime => "SESSION_EXPIRE_TIME".to_string(),
-            ConfigOptions::SpecialRoute => "SPECIAL_ROUTE".to_string(),
-            ConfigOptions::CookieName => "COOKIE_NAME".to_string(),
-            ConfigOptions::StaticDir => "STATIC_DIR".to_string(),
-            ConfigOptions::DbFile => "DB_FILE".to_string(),
-            ConfigOptions::Port => "PORT".to_string(),
-            ConfigOptions::Ip => "IP".to_string(),
+            ConfigOptions::SessionReapInterval => Some("300"),
+            ConfigOptions::SessionMaxLifetime => Some("2592000"),
+            ConfigOptions::LoginMaxLockoutTime => Some("3600"),
+            ConfigOptions::LoginLockoutTime => Some("60"),
+            ConfigOptions::LoginMaxAttemptsIp => Some("20"),
+            ConfigOptions::LoginMaxAttempts => Some("5"),
+            ConfigOptions::TrustedProxies => Some(""),
+            ConfigOptions::UpgradeIdleTimeout => Some("600"),
+            ConfigOptions::HealthEjectTime => Some("30"),
+            ConfigOptions::RemoteGroupsHeader => Some("X-Remote-Groups"),
+            ConfigOptions::RemoteAdminHeader => Some("X-Remote-Admin"),
+            ConfigOptions::RemoteUserHeader => Some("X-Remote-User"),
+            ConfigOptions::CookieSameSite => Some("lax"),
+            ConfigOptions::CookieSecure => Some("false"),
+            ConfigOptions::CookieDomain => Some(""),
+            _ => None,
         }
     }
 }
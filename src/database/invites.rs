@@ -0,0 +1,103 @@
+use super::*;
+
+use chrono::{Duration, Utc};
+use rusqlite::{OptionalExtension, Row};
+use serde::Serialize;
+use uuid::Uuid;
+
+// How long an invite can be used (7 days)
+const INVITE_EXPIRE_TIME: i64 = 7 * 24 * 60 * 60;
+
+// An invite for a new user from the invites table
+#[derive(Clone, Debug, Serialize)]
+pub struct Invite {
+    pub token: String,
+    pub username: String,
+    pub admin: bool,
+    pub expire_time: i64,
+}
+
+impl Invite {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            token: row.get("token")?,
+            username: row.get("username")?,
+            admin: row.get("admin")?,
+            expire_time: row.get("expire_time")?,
+        })
+    }
+}
+
+impl Db {
+    // Create an invite for a new user
+    pub async fn create_invite(&self, username: &str, admin: bool) -> Result<Invite, Error> {
+        // Create the invite
+        let invite = Invite {
+            token: Uuid::new_v4().simple().to_string(),
+            username: username.to_owned(),
+            admin,
+            expire_time: (Utc::now() + Duration::seconds(INVITE_EXPIRE_TIME)).timestamp(),
+        };
+
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Insert the invite
+        conn.execute(
+            "INSERT INTO invites (token, username, admin, expire_time) VALUES (?, ?, ?, ?);",
+            params![
+                invite.token,
+                invite.username,
+                invite.admin,
+                invite.expire_time
+            ],
+        )?;
+
+        // Return the invite
+        Ok(invite)
+    }
+
+    // Get all invites that can still be used
+    pub async fn get_invites(&self) -> Result<Vec<Invite>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare(
+            "SELECT * FROM invites WHERE expire_time > ? ORDER BY username COLLATE NOCASE;",
+        )?;
+        let invites = stmt
+            .query_map(params![Utc::now().timestamp()], Invite::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Return the invites
+        Ok(invites)
+    }
+
+    // Get an invite that can still be used by its token
+    pub async fn get_invite(&self, token: &str) -> Result<Option<Invite>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt =
+            conn.prepare("SELECT * FROM invites WHERE token = ? AND expire_time > ?;")?;
+        let invite = stmt
+            .query_row(params![token, Utc::now().timestamp()], Invite::from_row)
+            .optional()?;
+
+        // Return the invite if there is one
+        Ok(invite)
+    }
+
+    // Delete an invite
+    pub async fn delete_invite(&self, token: &str) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the invite
+        conn.execute("DELETE FROM invites WHERE token = ?;", params![token])?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+pub use proxies::{Balance, HealthCheck, Proxy, Target};
+pub use settings::TwoFactorPolicy;
+pub use tokens::ApiToken;
+pub use users::{parse_groups, User, UserChanges};
+
+use crate::*;
+
+use rusqlite::{params, Connection};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+
+mod invites;
+mod passkeys;
+mod proxies;
+mod sessions;
+mod settings;
+mod tokens;
+mod two_factor;
+mod users;
+
+#[derive(Clone)]
+pub struct Db {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Db {
+    pub async fn new(file: String) -> Result<Self, Error> {
+        // Create the database
+        let db = Db {
+            conn: Arc::new(Mutex::new(Connection::open(file)?)),
+        };
+
+        // Initialize the database
+        db.init().await?;
+
+        // Return the database
+        Ok(db)
+    }
+
+    async fn init(&self) -> Result<(), Error> {
+        // Get a connection from the pool
+        let conn = self.conn().await;
+
+        // Create the users table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                    username    VARCHAR(255) NOT NULL,
+                    password    VARCHAR(255) NOT NULL,
+                    admin       INTEGER NOT NULL,
+                    groups      VARCHAR(255) NOT NULL DEFAULT '',
+                    totp_secret VARCHAR(255),
+                    totp_last_step INTEGER NOT NULL DEFAULT 0
+                );",
+            params![],
+        )?;
+
+        // Add the two-factor columns older databases are missing
+        add_column(&conn, "users", "totp_secret", "VARCHAR(255)")?;
+        add_column(
+            &conn,
+            "users",
+            "totp_last_step",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        // Add the groups column older databases are missing
+        add_column(&conn, "users", "groups", "VARCHAR(255) NOT NULL DEFAULT ''")?;
+
+        // Make sure usernames are unique
+        conn.execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS users_username ON users (username);",
+            params![],
+        )?;
+
+        // Create the recovery codes table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS recovery_codes (
+                    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id     INTEGER NOT NULL,
+                    code_hash   VARCHAR(255) NOT NULL
+                );",
+            params![],
+        )?;
+
+        // Create the passkeys table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS passkeys (
+                    id             INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id        INTEGER NOT NULL,
+                    credential_id  VARCHAR(255) NOT NULL UNIQUE,
+                    public_key     BLOB NOT NULL,
+                    sign_count     INTEGER NOT NULL DEFAULT 0,
+                    name           VARCHAR(255) NOT NULL,
+                    created_time   INTEGER NOT NULL,
+                    last_used_time INTEGER
+                );",
+            params![],
+        )?;
+
+        // Create the settings table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS settings (
+                    key         VARCHAR(255) PRIMARY KEY,
+                    value       VARCHAR(255) NOT NULL
+                );",
+            params![],
+        )?;
+
+        // Create the invites table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS invites (
+                    token       VARCHAR(255) PRIMARY KEY,
+                    username    VARCHAR(255) NOT NULL,
+                    admin       INTEGER NOT NULL,
+                    expire_time INTEGER NOT NULL
+                );",
+            params![],
+        )?;
+
+        // Create the api tokens table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS api_tokens (
+                    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name         VARCHAR(255) NOT NULL,
+                    token_hash   VARCHAR(255) NOT NULL UNIQUE,
+                    created_by   VARCHAR(255) NOT NULL,
+                    created_time INTEGER NOT NULL
+                );",
+            params![],
+        )?;
+
+        // Create the sessions table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                    id          VARCHAR(255) PRIMARY KEY,
+                    user        VARCHAR(255) NOT NULL,
+                    admin       INTEGER NOT NULL,
+                    groups      VARCHAR(255) NOT NULL DEFAULT '',
+                    created_time INTEGER NOT NULL,
+                    renew_time  INTEGER NOT NULL,
+                    expire_time INTEGER NOT NULL
+                );",
+            params![],
+        )?;
+
+        // Sessions from older databases start their maximum lifetime now
+        if add_column(
+            &conn,
+            "sessions",
+            "created_time",
+            "INTEGER NOT NULL DEFAULT 0",
+        )? {
+            conn.execute(
+                "UPDATE sessions SET created_time = strftime('%s', 'now');",
+                params![],
+            )?;
+        }
+
+        // Sessions from older databases have no groups until the user logs in again
+        add_column(
+            &conn,
+            "sessions",
+            "groups",
+            "VARCHAR(255) NOT NULL DEFAULT ''",
+        )?;
+
+        // Create the proxy table
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS proxy (
+                    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name        VARCHAR(255) NOT NULL,
+                    balance     VARCHAR(255) NOT NULL DEFAULT 'round_robin',
+                    is_enabled  INTEGER NOT NULL DEFAULT 1,
+                    path_prefix VARCHAR(255) NOT NULL DEFAULT '/',
+                    strip_prefix INTEGER NOT NULL DEFAULT 0,
+                    rewrite_body INTEGER NOT NULL DEFAULT 0,
+                    rewrite_patterns TEXT NOT NULL DEFAULT '',
+                    health_path VARCHAR(255) NOT NULL DEFAULT '',
+                    health_status INTEGER NOT NULL DEFAULT 200,
+                    health_interval INTEGER NOT NULL DEFAULT 10,
+                    health_fails INTEGER NOT NULL DEFAULT 3,
+                    health_passes INTEGER NOT NULL DEFAULT 2
+                );",
+            params![],
+        )?;
+
+        // Add the columns older databases are missing
+        add_column(
+            &conn,
+            "proxy",
+            "path_prefix",
+            "VARCHAR(255) NOT NULL DEFAULT '/'",
+        )?;
+        add_column(&conn, "proxy", "strip_prefix", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column(&conn, "proxy", "rewrite_body", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column(
+            &conn,
+            "proxy",
+            "rewrite_patterns",
+            "TEXT NOT NULL DEFAULT ''",
+        )?;
+        add_column(
+            &conn,
+            "proxy",
+            "balance",
+            "VARCHAR(255) NOT NULL DEFAULT 'round_robin'",
+        )?;
+        add_column(
+            &conn,
+            "proxy",
+            "health_path",
+            "VARCHAR(255) NOT NULL DEFAULT ''",
+        )?;
+        add_column(
+            &conn,
+            "proxy",
+            "health_status",
+            "INTEGER NOT NULL DEFAULT 200",
+        )?;
+        add_column(
+            &conn,
+            "proxy",
+            "health_interval",
+            "INTEGER NOT NULL DEFAULT 10",
+        )?;
+        add_column(&conn, "proxy", "health_fails", "INTEGER NOT NULL DEFAULT 3")?;
+        add_column(
+            &conn,
+            "proxy",
+            "health_passes",
+            "INTEGER NOT NULL DEFAULT 2",
+        )?;
+
+        // Create the table with the upstreams of the proxy routes
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS proxy_targets (
+                    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                    proxy_id    INTEGER NOT NULL,
+                    host        VARCHAR(255) NOT NULL,
+                    port        INTEGER NOT NULL
+                );",
+            params![],
+        )?;
+
+        // Older databases kept a single upstream on the proxy route itself, move it to the targets
+        if has_column(&conn, "proxy", "host")? {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute_batch(
+                "INSERT INTO proxy_targets (proxy_id, host, port) SELECT id, host, port FROM proxy;
+                ALTER TABLE proxy DROP COLUMN host;
+                ALTER TABLE proxy DROP COLUMN port;",
+            )?;
+            tx.commit()?;
+        }
+
+        // Everything went well
+        Ok(())
+    }
+
+    async fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().await
+    }
+}
+
+// Add a column to an existing table if it isn't there yet, returns if it was added
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, Error> {
+    // Check if the column already exists
+    let exists = has_column(conn, table, column)?;
+
+    // Add the column
+    if !exists {
+        conn.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                table, column, definition
+            ),
+            params![],
+        )?;
+    }
+
+    Ok(!exists)
+}
+
+// Check if a table has a column
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({});", table))?;
+    let exists = stmt
+        .query_map(params![], |row| row.get::<_, String>("name"))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    Ok(exists)
+}
@@ -0,0 +1,152 @@
+use super::*;
+use crate::webauthn::NewCredential;
+
+use chrono::Utc;
+use rusqlite::{ErrorCode, OptionalExtension, Row};
+use serde::Serialize;
+
+// A passkey from the passkeys table (without the public key)
+#[derive(Clone, Debug, Serialize)]
+pub struct Passkey {
+    pub id: i64,
+    pub name: String,
+    pub credential_id: String,
+    pub created_time: i64,
+    pub last_used_time: Option<i64>,
+}
+
+impl Passkey {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            credential_id: row.get("credential_id")?,
+            created_time: row.get("created_time")?,
+            last_used_time: row.get("last_used_time")?,
+        })
+    }
+}
+
+// What is needed to check a login with a passkey
+#[derive(Clone, Debug)]
+pub struct PasskeyKey {
+    pub id: i64,
+    pub user: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+impl Db {
+    // Add a passkey to the account of a user
+    pub async fn create_passkey(
+        &self,
+        username: &str,
+        name: &str,
+        credential: &NewCredential,
+    ) -> Result<Passkey, Error> {
+        let created_time = Utc::now().timestamp();
+
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Insert the passkey, a credential can only belong to one account
+        let inserted = conn.execute(
+            "INSERT INTO passkeys (user_id, credential_id, public_key, sign_count, name, created_time)
+                VALUES ((SELECT id FROM users WHERE username = ?), ?, ?, ?, ?, ?);",
+            params![
+                username,
+                credential.credential_id,
+                credential.public_key,
+                credential.sign_count,
+                name,
+                created_time
+            ],
+        );
+        match inserted {
+            Ok(_) => (),
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == ErrorCode::ConstraintViolation =>
+            {
+                return Err(Error::Conflict("This passkey is already registered".into()))
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Passkey {
+            id: conn.last_insert_rowid(),
+            name: name.to_owned(),
+            credential_id: credential.credential_id.to_owned(),
+            created_time,
+            last_used_time: None,
+        })
+    }
+
+    // Get the passkeys of a user
+    pub async fn get_passkeys(&self, username: &str) -> Result<Vec<Passkey>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare(
+            "SELECT * FROM passkeys WHERE user_id = (SELECT id FROM users WHERE username = ?) ORDER BY id;",
+        )?;
+        let passkeys = stmt
+            .query_map(params![username], Passkey::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(passkeys)
+    }
+
+    // Get the passkey with a credential id, together with the user it belongs to
+    pub async fn find_passkey(&self, credential_id: &str) -> Result<Option<PasskeyKey>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare(
+            "SELECT passkeys.id, users.username, passkeys.public_key, passkeys.sign_count
+                FROM passkeys JOIN users ON users.id = passkeys.user_id
+                WHERE passkeys.credential_id = ?;",
+        )?;
+        let passkey = stmt
+            .query_row(params![credential_id], |row| {
+                Ok(PasskeyKey {
+                    id: row.get(0)?,
+                    user: row.get(1)?,
+                    public_key: row.get(2)?,
+                    sign_count: row.get(3)?,
+                })
+            })
+            .optional()?;
+
+        Ok(passkey)
+    }
+
+    // Remember the signature counter and when a passkey was last used
+    pub async fn use_passkey(&self, id: i64, sign_count: u32) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Update the passkey
+        conn.execute(
+            "UPDATE passkeys SET sign_count = ?, last_used_time = ? WHERE id = ?;",
+            params![sign_count, Utc::now().timestamp(), id],
+        )?;
+
+        Ok(())
+    }
+
+    // Delete a passkey of a user, returns if it existed
+    pub async fn delete_passkey(&self, username: &str, id: i64) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the passkey
+        let deleted = conn.execute(
+            "DELETE FROM passkeys WHERE id = ? AND user_id = (SELECT id FROM users WHERE username = ?);",
+            params![id, username],
+        )?;
+
+        Ok(deleted > 0)
+    }
+}
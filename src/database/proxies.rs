@@ -0,0 +1,480 @@
+use super::*;
+
+use rusqlite::{OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+// How the requests of a route are spread over its targets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    PowerOfTwo,
+    IpHash,
+    UserHash,
+}
+
+impl Balance {
+    pub const ALL: [Balance; 5] = [
+        Balance::RoundRobin,
+        Balance::LeastConnections,
+        Balance::PowerOfTwo,
+        Balance::IpHash,
+        Balance::UserHash,
+    ];
+
+    // Name of the strategy on the admin page
+    pub fn label(&self) -> &'static str {
+        match self {
+            Balance::RoundRobin => "round robin",
+            Balance::LeastConnections => "least connections",
+            Balance::PowerOfTwo => "random of two",
+            Balance::IpHash => "same ip, same upstream",
+            Balance::UserHash => "same user, same upstream",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Balance::RoundRobin => "round_robin",
+            Balance::LeastConnections => "least_connections",
+            Balance::PowerOfTwo => "power_of_two",
+            Balance::IpHash => "ip_hash",
+            Balance::UserHash => "user_hash",
+        }
+    }
+}
+
+impl std::str::FromStr for Balance {
+    type Err = Error;
+
+    fn from_str(balance: &str) -> Result<Self, Error> {
+        match balance {
+            "round_robin" => Ok(Balance::RoundRobin),
+            "least_connections" => Ok(Balance::LeastConnections),
+            "power_of_two" => Ok(Balance::PowerOfTwo),
+            "ip_hash" => Ok(Balance::IpHash),
+            "user_hash" => Ok(Balance::UserHash),
+            _ => Err(Error::BadRequest(format!(
+                "{} is not a valid balancing strategy, expected round_robin, least_connections, power_of_two, ip_hash or user_hash",
+                balance
+            ))),
+        }
+    }
+}
+
+// An upstream a route forwards to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    // Get the value for the Host header send to the upstream
+    pub fn authority(&self) -> String {
+        match self.port {
+            80 | 443 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+
+    // Upstreams on the https port are reached over tls, all others over plain http
+    fn scheme(&self) -> &'static str {
+        match self.port {
+            443 => "https",
+            _ => "http",
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl std::str::FromStr for Target {
+    type Err = &'static str;
+
+    // Parse a target like 10.0.0.1:3000 or [::1]:3000
+    fn from_str(target: &str) -> Result<Self, &'static str> {
+        let invalid = "Please enter the upstreams as host:port, separated by commas.";
+        let (host, port) = target.trim().rsplit_once(':').ok_or(invalid)?;
+
+        Ok(Target {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| invalid)?,
+        })
+    }
+}
+
+// How a route checks that its targets are up, an empty path turns the active check off
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    pub path: String,
+    // Status the target has to answer with
+    pub status: u16,
+    // Seconds between checks
+    pub interval: u32,
+    // Failures in a row before a target is taken out
+    pub fails: u32,
+    // Passed checks in a row before a target is taken back
+    pub passes: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            status: 200,
+            interval: 10,
+            fails: 3,
+            passes: 2,
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn is_active(&self) -> bool {
+        !self.path.is_empty()
+    }
+
+    fn validate(&mut self) -> Result<(), &'static str> {
+        self.path = self.path.trim().to_owned();
+
+        if self.is_active() && !self.path.starts_with('/') {
+            return Err("The health check path has to start with a /.");
+        }
+        if !(100..=599).contains(&self.status) {
+            return Err("Please enter a health check status between 100 and 599.");
+        }
+        if self.interval == 0 || self.fails == 0 || self.passes == 0 {
+            return Err("The health check interval and thresholds have to be at least 1.");
+        }
+
+        Ok(())
+    }
+}
+
+// A proxy route from the proxy table, with its targets from the proxy_targets table
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Proxy {
+    pub id: i64,
+    pub name: String,
+    pub targets: Vec<Target>,
+    pub balance: Balance,
+    pub path_prefix: String,
+    pub strip_prefix: bool,
+    pub is_enabled: bool,
+    pub rewrite_body: bool,
+    pub rewrite_patterns: String,
+    pub health: HealthCheck,
+}
+
+impl Proxy {
+    // Build the uri on the given target for the path and query
+    pub fn uri(&self, target: &Target, path_query: &str) -> String {
+        // Strip the path prefix if the route asks for it
+        let path_query = match self.strip_prefix {
+            true => self.strip(path_query),
+            false => path_query.to_owned(),
+        };
+
+        format!("{}://{}{}", target.scheme(), target.authority(), path_query)
+    }
+
+    // Get the uri of the health check on one of the targets
+    pub fn health_uri(&self, target: &Target) -> String {
+        format!(
+            "{}://{}{}",
+            target.scheme(),
+            target.authority(),
+            self.health.path
+        )
+    }
+
+    // Check if the route answers to every virtual host
+    pub fn is_wildcard(&self) -> bool {
+        self.name == "*"
+    }
+
+    // Get the path prefix without a trailing slash ("" for the root)
+    pub fn prefix(&self) -> &str {
+        self.path_prefix.trim_end_matches('/')
+    }
+
+    // Check if the path falls under the path prefix of the route
+    pub fn matches_path(&self, path: &str) -> bool {
+        let prefix = self.prefix();
+        match path.strip_prefix(prefix) {
+            Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    // Get the extra texts to rewrite in pages, one per line
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.rewrite_patterns
+            .lines()
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+    }
+
+    // Check if the path prefix lies inside the special route
+    pub fn collides_with(&self, special_route: &str) -> bool {
+        let special_route = special_route.trim_end_matches('/');
+        let prefix = self.prefix();
+        prefix == special_route || prefix.starts_with(&format!("{}/", special_route))
+    }
+
+    // Normalize and check the fields before the route is saved
+    pub fn validate(&mut self, special_route: &str) -> Result<(), &'static str> {
+        // Normalize the fields
+        self.name = self.name.trim().to_lowercase();
+        for target in &mut self.targets {
+            target.host = target.host.trim().to_owned();
+        }
+        self.path_prefix = match self.path_prefix.trim() {
+            "" => "/".to_owned(),
+            prefix => prefix.to_owned(),
+        };
+
+        self.rewrite_patterns = self.patterns().collect::<Vec<_>>().join("\n");
+
+        // Check the fields
+        if self.name.is_empty() || self.name.contains(char::is_whitespace) {
+            return Err("Please enter a host name without spaces, or * for every host.");
+        }
+        if self.targets.is_empty() {
+            return Err("Please enter at least one upstream.");
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            if target.host.is_empty() || target.host.contains(char::is_whitespace) {
+                return Err("Please enter the address of the upstream.");
+            }
+            if target.port == 0 {
+                return Err("Please enter a port between 1 and 65535.");
+            }
+            if self.targets[..i].contains(target) {
+                return Err("Every upstream can only be added once.");
+            }
+        }
+        if !self.path_prefix.starts_with('/') {
+            return Err("The path prefix has to start with a /.");
+        }
+
+        self.health.validate()?;
+        if self.rewrite_body && self.prefix().is_empty() {
+            return Err("Only routes with a path prefix can rewrite their pages.");
+        }
+        if self.patterns().any(|pattern| !pattern.contains('/')) {
+            return Err("Every rewrite pattern needs a / for the path prefix to go in front of.");
+        }
+
+        // The special route is reserved for proxrs
+        if self.collides_with(special_route) {
+            return Err("The path prefix can't be inside the special route of proxrs.");
+        }
+
+        Ok(())
+    }
+
+    // Remove the path prefix from the path, keeping the query intact
+    fn strip(&self, path_query: &str) -> String {
+        let rest = path_query.strip_prefix(self.prefix()).unwrap_or(path_query);
+
+        match rest.starts_with('/') {
+            true => rest.to_owned(),
+            false => format!("/{}", rest),
+        }
+    }
+
+    // The targets are loaded separately
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            targets: Vec::new(),
+            balance: row.get::<_, String>("balance")?.parse().unwrap_or_default(),
+            path_prefix: row.get("path_prefix")?,
+            strip_prefix: row.get("strip_prefix")?,
+            is_enabled: row.get("is_enabled")?,
+            rewrite_body: row.get("rewrite_body")?,
+            rewrite_patterns: row.get("rewrite_patterns")?,
+            health: HealthCheck {
+                path: row.get("health_path")?,
+                status: row.get("health_status")?,
+                interval: row.get("health_interval")?,
+                fails: row.get("health_fails")?,
+                passes: row.get("health_passes")?,
+            },
+        })
+    }
+}
+
+impl Db {
+    // Get the enabled proxy routes for a virtual host name (including wildcard routes)
+    pub async fn get_proxies_for_host(&self, name: &str) -> Result<Vec<Proxy>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare(
+            "SELECT * FROM proxy WHERE (name = ? COLLATE NOCASE OR name = '*') AND is_enabled = 1;",
+        )?;
+        let mut proxies = stmt
+            .query_map(params![name], Proxy::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        load_targets(&conn, &mut proxies)?;
+
+        // Return the proxy routes
+        Ok(proxies)
+    }
+
+    // Get all proxy routes
+    pub async fn get_proxies(&self) -> Result<Vec<Proxy>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt =
+            conn.prepare("SELECT * FROM proxy ORDER BY name COLLATE NOCASE, path_prefix;")?;
+        let mut proxies = stmt
+            .query_map(params![], Proxy::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        load_targets(&conn, &mut proxies)?;
+
+        // Return the proxy routes
+        Ok(proxies)
+    }
+
+    // Get a proxy route by id
+    pub async fn get_proxy(&self, id: i64) -> Result<Option<Proxy>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM proxy WHERE id = ?;")?;
+        let mut proxy = stmt.query_row(params![id], Proxy::from_row).optional()?;
+        if let Some(proxy) = &mut proxy {
+            load_targets(&conn, std::slice::from_mut(proxy))?;
+        }
+
+        // Return the proxy route if there is one
+        Ok(proxy)
+    }
+
+    // Create a new proxy route and return its id (the id of the given route is ignored)
+    pub async fn create_proxy(&self, proxy: &Proxy) -> Result<i64, Error> {
+        // Get a connection
+        let mut conn = self.conn().await;
+
+        // Insert the proxy route and its targets in one go
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO proxy (name, balance, path_prefix, strip_prefix, is_enabled, rewrite_body, rewrite_patterns, health_path, health_status, health_interval, health_fails, health_passes) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            params![
+                proxy.name,
+                proxy.balance.as_str(),
+                proxy.path_prefix,
+                proxy.strip_prefix,
+                proxy.is_enabled,
+                proxy.rewrite_body,
+                proxy.rewrite_patterns,
+                proxy.health.path,
+                proxy.health.status,
+                proxy.health.interval,
+                proxy.health.fails,
+                proxy.health.passes
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        save_targets(&tx, id, &proxy.targets)?;
+        tx.commit()?;
+
+        // Return the id of the proxy route
+        Ok(id)
+    }
+
+    // Update an existing proxy route
+    pub async fn update_proxy(&self, proxy: &Proxy) -> Result<(), Error> {
+        // Get a connection
+        let mut conn = self.conn().await;
+
+        // Update the proxy route and replace its targets in one go
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE proxy SET name = ?, balance = ?, path_prefix = ?, strip_prefix = ?, is_enabled = ?, rewrite_body = ?, rewrite_patterns = ?, health_path = ?, health_status = ?, health_interval = ?, health_fails = ?, health_passes = ? WHERE id = ?;",
+            params![
+                proxy.name,
+                proxy.balance.as_str(),
+                proxy.path_prefix,
+                proxy.strip_prefix,
+                proxy.is_enabled,
+                proxy.rewrite_body,
+                proxy.rewrite_patterns,
+                proxy.health.path,
+                proxy.health.status,
+                proxy.health.interval,
+                proxy.health.fails,
+                proxy.health.passes,
+                proxy.id
+            ],
+        )?;
+        save_targets(&tx, proxy.id, &proxy.targets)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // Delete a proxy route
+    pub async fn delete_proxy(&self, id: i64) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the proxy route and its targets together, so no targets are left behind
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM proxy WHERE id = ?;", params![id])?;
+        tx.execute("DELETE FROM proxy_targets WHERE proxy_id = ?;", params![id])?;
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+// Fill in the targets of the proxy routes, in the order they were entered
+fn load_targets(conn: &Connection, proxies: &mut [Proxy]) -> Result<(), Error> {
+    let mut stmt =
+        conn.prepare("SELECT host, port FROM proxy_targets WHERE proxy_id = ? ORDER BY id;")?;
+    for proxy in proxies {
+        proxy.targets = stmt
+            .query_map(params![proxy.id], |row| {
+                Ok(Target {
+                    host: row.get("host")?,
+                    port: row.get("port")?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+
+    Ok(())
+}
+
+// Replace the targets of a proxy route
+fn save_targets(conn: &Connection, proxy_id: i64, targets: &[Target]) -> Result<(), Error> {
+    conn.execute(
+        "DELETE FROM proxy_targets WHERE proxy_id = ?;",
+        params![proxy_id],
+    )?;
+    for target in targets {
+        conn.execute(
+            "INSERT INTO proxy_targets (proxy_id, host, port) VALUES (?, ?, ?);",
+            params![proxy_id, target.host, target.port],
+        )?;
+    }
+
+    Ok(())
+}
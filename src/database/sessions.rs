@@ -0,0 +1,116 @@
+use super::*;
+
+use chrono::{TimeZone, Utc};
+use rusqlite::Row;
+
+fn session_from_row(row: &Row) -> Result<Session, rusqlite::Error> {
+    let timestamp = |column: &str| -> Result<_, rusqlite::Error> {
+        Ok(Utc
+            .timestamp_opt(row.get(column)?, 0)
+            .single()
+            .unwrap_or_default())
+    };
+
+    Ok(Session {
+        id: row.get("id")?,
+        user: row.get("user")?,
+        admin: row.get("admin")?,
+        groups: users::split_groups(&row.get::<_, String>("groups")?),
+        created_time: timestamp("created_time")?,
+        renew_time: timestamp("renew_time")?,
+        expire_time: timestamp("expire_time")?,
+    })
+}
+
+impl Db {
+    // Get all sessions that are still valid
+    pub async fn get_sessions(&self) -> Result<Vec<Session>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM sessions WHERE expire_time > ?;")?;
+        let sessions = stmt
+            .query_map(params![Utc::now().timestamp()], session_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Return the sessions
+        Ok(sessions)
+    }
+
+    // Store a new session
+    pub async fn insert_session(&self, session: &Session) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Insert the session
+        conn.execute(
+            "INSERT INTO sessions (id, user, admin, groups, created_time, renew_time, expire_time) VALUES (?, ?, ?, ?, ?, ?, ?);",
+            params![
+                session.id,
+                session.user,
+                session.admin,
+                session.groups.join(","),
+                session.created_time.timestamp(),
+                session.renew_time.timestamp(),
+                session.expire_time.timestamp()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    // Store the expire times of a session
+    pub async fn update_session(&self, session: &Session) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Update the session
+        conn.execute(
+            "UPDATE sessions SET renew_time = ?, expire_time = ? WHERE id = ?;",
+            params![
+                session.renew_time.timestamp(),
+                session.expire_time.timestamp(),
+                session.id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    // Delete a session
+    pub async fn delete_session(&self, id: &str) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the session
+        conn.execute("DELETE FROM sessions WHERE id = ?;", params![id])?;
+
+        Ok(())
+    }
+
+    // Delete all sessions of a user
+    pub async fn delete_user_sessions(&self, user: &str) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the sessions
+        conn.execute("DELETE FROM sessions WHERE user = ?;", params![user])?;
+
+        Ok(())
+    }
+
+    // Delete all expired sessions, returns how many were deleted
+    pub async fn delete_expired_sessions(&self) -> Result<usize, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the sessions
+        let deleted = conn.execute(
+            "DELETE FROM sessions WHERE expire_time <= ?;",
+            params![Utc::now().timestamp()],
+        )?;
+
+        Ok(deleted)
+    }
+}
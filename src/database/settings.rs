@@ -0,0 +1,93 @@
+use super::*;
+
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+// Who has to use two-factor authentication to log in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TwoFactorPolicy {
+    #[default]
+    Optional,
+    Admins,
+    All,
+}
+
+impl TwoFactorPolicy {
+    // Check if a user has to use two-factor authentication
+    pub fn required(&self, admin: bool) -> bool {
+        match self {
+            TwoFactorPolicy::Optional => false,
+            TwoFactorPolicy::Admins => admin,
+            TwoFactorPolicy::All => true,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TwoFactorPolicy::Optional => "optional",
+            TwoFactorPolicy::Admins => "admins",
+            TwoFactorPolicy::All => "all",
+        }
+    }
+}
+
+impl std::str::FromStr for TwoFactorPolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self, Error> {
+        match policy {
+            "optional" => Ok(TwoFactorPolicy::Optional),
+            "admins" => Ok(TwoFactorPolicy::Admins),
+            "all" => Ok(TwoFactorPolicy::All),
+            _ => Err(Error::BadRequest(format!(
+                "{} is not a valid two-factor policy, expected optional, admins or all",
+                policy
+            ))),
+        }
+    }
+}
+
+const TWO_FACTOR_POLICY: &str = "two_factor_policy";
+
+impl Db {
+    // Get a setting from the settings table
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?;")?;
+        let value = stmt.query_row(params![key], |row| row.get(0)).optional()?;
+
+        Ok(value)
+    }
+
+    // Change a setting in the settings table
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Insert or replace the setting
+        conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?, ?)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value;",
+            params![key, value],
+        )?;
+
+        Ok(())
+    }
+
+    // Get who has to use two-factor authentication
+    pub async fn get_two_factor_policy(&self) -> Result<TwoFactorPolicy, Error> {
+        match self.get_setting(TWO_FACTOR_POLICY).await? {
+            Some(policy) => policy.parse(),
+            None => Ok(TwoFactorPolicy::default()),
+        }
+    }
+
+    // Change who has to use two-factor authentication
+    pub async fn set_two_factor_policy(&self, policy: TwoFactorPolicy) -> Result<(), Error> {
+        self.set_setting(TWO_FACTOR_POLICY, policy.as_str()).await
+    }
+}
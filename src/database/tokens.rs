@@ -0,0 +1,110 @@
+use super::*;
+
+use chrono::Utc;
+use rusqlite::{OptionalExtension, Row};
+use serde::Serialize;
+use sha2::Digest;
+use uuid::Uuid;
+
+// An api token from the api_tokens table (without the token itself)
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub created_by: String,
+    pub created_time: i64,
+}
+
+impl ApiToken {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            created_by: row.get("created_by")?,
+            created_time: row.get("created_time")?,
+        })
+    }
+}
+
+// Api tokens are random, so a plain hash is enough to keep them safe at rest
+fn hash_token(token: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(token);
+    hex::encode(hasher.finalize())
+}
+
+impl Db {
+    // Create an api token, the token itself is only returned here
+    pub async fn create_api_token(
+        &self,
+        name: &str,
+        created_by: &str,
+    ) -> Result<(ApiToken, String), Error> {
+        // Generate the token
+        let token = format!(
+            "proxrs_{}{}",
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+        let created_time = Utc::now().timestamp();
+
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Insert the token
+        conn.execute(
+            "INSERT INTO api_tokens (name, token_hash, created_by, created_time) VALUES (?, ?, ?, ?);",
+            params![name, hash_token(&token), created_by, created_time],
+        )?;
+
+        // Return the token
+        let api_token = ApiToken {
+            id: conn.last_insert_rowid(),
+            name: name.to_owned(),
+            created_by: created_by.to_owned(),
+            created_time,
+        };
+        Ok((api_token, token))
+    }
+
+    // Get all api tokens
+    pub async fn get_api_tokens(&self) -> Result<Vec<ApiToken>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM api_tokens ORDER BY id;")?;
+        let tokens = stmt
+            .query_map(params![], ApiToken::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Return the tokens
+        Ok(tokens)
+    }
+
+    // Get the api token belonging to a token
+    pub async fn validate_api_token(&self, token: &str) -> Result<Option<ApiToken>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM api_tokens WHERE token_hash = ?;")?;
+        let api_token = stmt
+            .query_row(params![hash_token(token)], ApiToken::from_row)
+            .optional()?;
+
+        // Return the api token if there is one
+        Ok(api_token)
+    }
+
+    // Delete an api token, returns if it existed
+    pub async fn delete_api_token(&self, id: i64) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the token
+        let deleted = conn.execute("DELETE FROM api_tokens WHERE id = ?;", params![id])?;
+
+        Ok(deleted > 0)
+    }
+}
@@ -0,0 +1,198 @@
+use super::*;
+
+use chrono::Utc;
+use rand::{rngs::OsRng, Rng};
+use rusqlite::OptionalExtension;
+use sha2::Digest;
+
+// How many recovery codes a user gets
+const RECOVERY_CODES: usize = 10;
+
+// Characters recovery codes are made of, without ones that are easily mixed up
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+// Recovery codes are random, so a plain hash is enough to keep them safe at rest
+fn hash_recovery_code(code: &str) -> String {
+    // Ignore the dash and capitals people may type
+    let code = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(code);
+    hex::encode(hasher.finalize())
+}
+
+// Generate a recovery code like abcd-efgh-jkmn
+fn generate_recovery_code() -> String {
+    (0..3)
+        .map(|_| {
+            (0..4)
+                .map(|_| RECOVERY_ALPHABET[OsRng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+impl Db {
+    // Check a second factor, either a code from the authenticator app or an unused recovery code
+    pub async fn validate_two_factor(&self, username: &str, code: &str) -> Result<bool, Error> {
+        let secret = match self.get_totp_secret(username).await? {
+            Some(secret) => secret,
+            None => return Ok(false),
+        };
+
+        // Authenticator codes can only be used once
+        if let Some(step) = totp::verify(&secret, code, Utc::now().timestamp()) {
+            return self.use_totp_step(username, step).await;
+        }
+
+        self.use_recovery_code(username, code).await
+    }
+
+    // Get the totp secret of a user, if two-factor authentication is enabled
+    pub async fn get_totp_secret(&self, username: &str) -> Result<Option<String>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT totp_secret FROM users WHERE username = ?;")?;
+        let secret = stmt
+            .query_row(params![username], |row| row.get::<_, Option<String>>(0))
+            .optional()?;
+
+        Ok(secret.flatten())
+    }
+
+    // Enable two-factor authentication with a secret, or disable it with None
+    // Turning it off also removes the recovery codes
+    pub async fn set_totp_secret(&self, username: &str, secret: Option<&str>) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Update the user
+        conn.execute(
+            "UPDATE users SET totp_secret = ?, totp_last_step = 0 WHERE username = ?;",
+            params![secret, username],
+        )?;
+        if secret.is_none() {
+            conn.execute(
+                "DELETE FROM recovery_codes WHERE user_id = (SELECT id FROM users WHERE username = ?);",
+                params![username],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Mark a totp time step as used, returns false when it (or a later one) was used already
+    pub async fn use_totp_step(&self, username: &str, step: i64) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Only move forward, so a code can't be replayed
+        let updated = conn.execute(
+            "UPDATE users SET totp_last_step = ? WHERE username = ? AND totp_last_step < ?;",
+            params![step, username, step],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    // Replace the recovery codes of a user, the codes themselves are only returned here
+    pub async fn create_recovery_codes(&self, username: &str) -> Result<Vec<String>, Error> {
+        let codes = (0..RECOVERY_CODES)
+            .map(|_| generate_recovery_code())
+            .collect::<Vec<_>>();
+
+        // Get a connection
+        let mut conn = self.conn().await;
+
+        // Replace the codes in one go
+        let tx = conn.transaction()?;
+        let user_id: i64 = tx.query_row(
+            "SELECT id FROM users WHERE username = ?;",
+            params![username],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "DELETE FROM recovery_codes WHERE user_id = ?;",
+            params![user_id],
+        )?;
+        for code in &codes {
+            tx.execute(
+                "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?);",
+                params![user_id, hash_recovery_code(code)],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(codes)
+    }
+
+    // Use up a recovery code, returns if it was valid
+    pub async fn use_recovery_code(&self, username: &str, code: &str) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Delete the code
+        let deleted = conn.execute(
+            "DELETE FROM recovery_codes
+                WHERE user_id = (SELECT id FROM users WHERE username = ?) AND code_hash = ?;",
+            params![username, hash_recovery_code(code)],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // Count the recovery codes a user has left
+    pub async fn count_recovery_codes(&self, username: &str) -> Result<i64, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let count = conn.query_row(
+            "SELECT COUNT(*) FROM recovery_codes WHERE user_id = (SELECT id FROM users WHERE username = ?);",
+            params![username],
+            |row| row.get(0),
+        )?;
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn user_with_two_factor() -> (Db, String) {
+        let db = Db::new(":memory:".to_string()).await.unwrap();
+        db.create_user("alice", "password", false).await.unwrap();
+        let secret = totp::generate_secret();
+        db.set_totp_secret("alice", Some(&secret)).await.unwrap();
+
+        (db, secret)
+    }
+
+    #[tokio::test]
+    async fn totp_steps_only_move_forward() {
+        let (db, _) = user_with_two_factor().await;
+
+        assert!(db.use_totp_step("alice", 10).await.unwrap());
+        assert!(!db.use_totp_step("alice", 10).await.unwrap());
+        assert!(!db.use_totp_step("alice", 9).await.unwrap());
+        assert!(db.use_totp_step("alice", 11).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn totp_codes_cant_be_reused() {
+        let (db, secret) = user_with_two_factor().await;
+        let code = totp::code_at(&secret, Utc::now().timestamp()).unwrap();
+
+        assert!(db.validate_two_factor("alice", &code).await.unwrap());
+        assert!(!db.validate_two_factor("alice", &code).await.unwrap());
+    }
+}
@@ -0,0 +1,381 @@
+use super::*;
+use crate::password::Verified;
+
+use rusqlite::{OptionalExtension, Row};
+use serde::Serialize;
+
+// A user from the users table (without the password hash)
+#[derive(Clone, Debug, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub admin: bool,
+    pub groups: Vec<String>,
+    pub two_factor: bool,
+}
+
+impl User {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            username: row.get("username")?,
+            admin: row.get("admin")?,
+            groups: split_groups(&row.get::<_, String>("groups")?),
+            two_factor: row.get::<_, Option<String>>("totp_secret")?.is_some(),
+        })
+    }
+}
+
+// Clean up the groups of a user, group names may only use letters, digits, "-", "_" and "."
+pub fn parse_groups<S: AsRef<str>>(
+    groups: impl IntoIterator<Item = S>,
+) -> Result<Vec<String>, Error> {
+    let mut parsed = Vec::new();
+    for group in groups {
+        let group = group.as_ref().trim();
+        if group.is_empty() {
+            continue;
+        }
+        if !group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(Error::BadRequest(format!(
+                "{} is not a valid group name, only letters, digits, \"-\", \"_\" and \".\" are allowed",
+                group
+            )));
+        }
+        parsed.push(group.to_owned());
+    }
+    parsed.sort();
+    parsed.dedup();
+
+    Ok(parsed)
+}
+
+// Groups are stored comma separated
+pub(super) fn split_groups(groups: &str) -> Vec<String> {
+    groups
+        .split(',')
+        .filter(|group| !group.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Accounts that older versions created on the first run, with the username as the password
+const SEEDED_ACCOUNTS: [&str; 2] = ["stan", "admin"];
+
+// Changes to a user that are saved together, fields that are None stay the same
+#[derive(Default)]
+pub struct UserChanges<'a> {
+    pub admin: Option<bool>,
+    pub groups: Option<&'a [String]>,
+    pub password: Option<&'a str>,
+    pub reset_two_factor: bool,
+}
+
+impl Db {
+    pub async fn validate_user(&self, username: &str, password: &str) -> Result<bool, Error> {
+        // Get the stored password hash
+        let user = {
+            let conn = self.conn().await;
+            let mut stmt = conn.prepare("SELECT id, password FROM users WHERE username = ?;")?;
+            stmt.query_row(params![username], |row| {
+                Ok((row.get::<_, i64>("id")?, row.get::<_, String>("password")?))
+            })
+            .optional()?
+        };
+
+        // Still do the Argon2 work when the user doesn't exist, so the timing doesn't tell
+        let (id, stored) = match user {
+            Some(user) => user,
+            None => {
+                password::verify(password, password::DUMMY_HASH).await;
+                return Ok(false);
+            }
+        };
+
+        // Verify the password
+        match password::verify(password, &stored).await {
+            Verified::Valid => Ok(true),
+            Verified::Invalid => Ok(false),
+            Verified::ValidNeedsRehash => {
+                // Upgrade the legacy hash now that we know the password
+                let hash = password::hash(password).await?;
+                let conn = self.conn().await;
+                conn.execute(
+                    "UPDATE users SET password = ? WHERE id = ?;",
+                    params![hash, id],
+                )?;
+
+                Ok(true)
+            }
+        }
+    }
+
+    pub async fn is_admin(&self, username: &str) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM users WHERE username = ? AND admin = 1;")?;
+        let mut rows = stmt.query(params![username])?;
+
+        // Return if the user exists
+        Ok(rows.next()?.is_some())
+    }
+
+    // Check if there is at least one user
+    pub async fn has_users(&self) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM users LIMIT 1;")?;
+        let mut rows = stmt.query(params![])?;
+
+        // Return if there is a user
+        Ok(rows.next()?.is_some())
+    }
+
+    // Create a new user and return its id
+    pub async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        admin: bool,
+    ) -> Result<i64, Error> {
+        // Hash the password
+        let hash = password::hash(password).await?;
+
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Insert the user
+        conn.execute(
+            "INSERT INTO users (username, password, admin) VALUES (?, ?, ?);",
+            params![username, hash, admin],
+        )?;
+
+        // Return the id of the user
+        Ok(conn.last_insert_rowid())
+    }
+
+    // Get all users ordered by username
+    pub async fn get_users(&self) -> Result<Vec<User>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM users ORDER BY username COLLATE NOCASE;")?;
+        let users = stmt
+            .query_map(params![], User::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Return the users
+        Ok(users)
+    }
+
+    // Get a user by id
+    pub async fn get_user(&self, id: i64) -> Result<Option<User>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM users WHERE id = ?;")?;
+        let user = stmt.query_row(params![id], User::from_row).optional()?;
+
+        // Return the user if there is one
+        Ok(user)
+    }
+
+    // Check if a username is already taken
+    pub async fn username_exists(&self, username: &str) -> Result<bool, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT * FROM users WHERE username = ?;")?;
+        let mut rows = stmt.query(params![username])?;
+
+        // Return if the user exists
+        Ok(rows.next()?.is_some())
+    }
+
+    // Change if a user is an admin
+    pub async fn set_admin(&self, id: i64, admin: bool) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Update the user
+        conn.execute(
+            "UPDATE users SET admin = ? WHERE id = ?;",
+            params![admin, id],
+        )?;
+
+        Ok(())
+    }
+
+    // Get the groups of a user
+    pub async fn get_groups(&self, username: &str) -> Result<Vec<String>, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let mut stmt = conn.prepare("SELECT groups FROM users WHERE username = ?;")?;
+        let groups = stmt
+            .query_row(params![username], |row| row.get::<_, String>(0))
+            .optional()?;
+
+        Ok(groups
+            .map(|groups| split_groups(&groups))
+            .unwrap_or_default())
+    }
+
+    // Replace the groups of a user
+    pub async fn set_groups(&self, id: i64, groups: &[String]) -> Result<(), Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Update the user
+        conn.execute(
+            "UPDATE users SET groups = ? WHERE id = ?;",
+            params![groups.join(","), id],
+        )?;
+
+        Ok(())
+    }
+
+    // Set a new password for a user
+    pub async fn set_password(&self, id: i64, password: &str) -> Result<(), Error> {
+        // Hash the password
+        let hash = password::hash(password).await?;
+
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Update the user
+        conn.execute(
+            "UPDATE users SET password = ? WHERE id = ?;",
+            params![hash, id],
+        )?;
+
+        Ok(())
+    }
+
+    // Save several changes to a user at once, so either all or none of them are made
+    pub async fn update_user(&self, id: i64, changes: &UserChanges<'_>) -> Result<(), Error> {
+        // Hash the password before the connection is taken
+        let hash = match changes.password {
+            Some(password) => Some(password::hash(password).await?),
+            None => None,
+        };
+
+        // Get a connection
+        let mut conn = self.conn().await;
+        let tx = conn.transaction()?;
+
+        // Update the user
+        if let Some(admin) = changes.admin {
+            tx.execute(
+                "UPDATE users SET admin = ? WHERE id = ?;",
+                params![admin, id],
+            )?;
+        }
+        if let Some(groups) = changes.groups {
+            tx.execute(
+                "UPDATE users SET groups = ? WHERE id = ?;",
+                params![groups.join(","), id],
+            )?;
+        }
+        if let Some(hash) = hash {
+            tx.execute(
+                "UPDATE users SET password = ? WHERE id = ?;",
+                params![hash, id],
+            )?;
+        }
+        if changes.reset_two_factor {
+            tx.execute(
+                "UPDATE users SET totp_secret = NULL, totp_last_step = 0 WHERE id = ?;",
+                params![id],
+            )?;
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = ?;", params![id])?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // Delete a user
+    pub async fn delete_user(&self, id: i64) -> Result<(), Error> {
+        // Get a connection
+        let mut conn = self.conn().await;
+
+        // Delete the user, their recovery codes and passkeys together, so nothing is left behind
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM users WHERE id = ?;", params![id])?;
+        tx.execute("DELETE FROM recovery_codes WHERE user_id = ?;", params![id])?;
+        tx.execute("DELETE FROM passkeys WHERE user_id = ?;", params![id])?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // Get the accounts older versions created that still have their well known password
+    pub async fn seeded_accounts(&self) -> Result<Vec<String>, Error> {
+        let mut seeded = Vec::new();
+        for username in SEEDED_ACCOUNTS {
+            // Get the stored password hash
+            let stored = {
+                let conn = self.conn().await;
+                let mut stmt = conn.prepare("SELECT password FROM users WHERE username = ?;")?;
+                stmt.query_row(params![username], |row| row.get::<_, String>(0))
+                    .optional()?
+            };
+
+            if let Some(stored) = stored {
+                if password::verify(username, &stored).await != Verified::Invalid {
+                    seeded.push(username.to_owned());
+                }
+            }
+        }
+
+        Ok(seeded)
+    }
+
+    // Count the admins
+    pub async fn count_admins(&self) -> Result<i64, Error> {
+        // Get a connection
+        let conn = self.conn().await;
+
+        // Do the query
+        let count = conn.query_row(
+            "SELECT COUNT(*) FROM users WHERE admin = 1;",
+            params![],
+            |row| row.get(0),
+        )?;
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn finds_seeded_accounts_with_their_old_password() {
+        let db = Db::new(":memory:".to_string()).await.unwrap();
+        assert!(db.seeded_accounts().await.unwrap().is_empty());
+
+        let stan = db.create_user("stan", "stan", true).await.unwrap();
+        db.create_user("admin", "admin", false).await.unwrap();
+        db.create_user("root", "root", true).await.unwrap();
+        assert_eq!(db.seeded_accounts().await.unwrap(), ["stan", "admin"]);
+
+        db.set_password(stan, "correct horse battery staple")
+            .await
+            .unwrap();
+        assert_eq!(db.seeded_accounts().await.unwrap(), ["admin"]);
+    }
+}
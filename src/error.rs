@@ -1,3 +1,6 @@
+use axum::response::{IntoResponse, Response};
+use hyper::StatusCode;
+use serde_json::json;
 use thiserror::Error;
 
 // Global error type (inherits from all other errors)
@@ -22,6 +25,73 @@ pub enum Error {
     // Database error
     #[error("Database: {0}")]
     Database(#[from] rusqlite::Error),
+
+    // Password hashing error
+    #[error("Password hash: {0}")]
+    PasswordHash(#[from] argon2::password_hash::Error),
+
+    // A background task didn't finish
+    #[error("Task: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    // The request is not valid
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    // No (valid) credentials were given
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    // The credentials don't allow this
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    // The requested resource doesn't exist
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    // The request conflicts with the current state
+    #[error("Conflict: {0}")]
+    Conflict(String),
+}
+
+impl Error {
+    // Get the http status code that belongs to the error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Turn the error into a json error body
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        // Don't leak internal errors to the client
+        let message = match status {
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                eprintln!("{}", self);
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        let body = json!({
+            "error": {
+                "status": status.as_u16(),
+                "message": message,
+            }
+        });
+
+        (status, axum::Json(body)).into_response()
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,296 @@
+use crate::*;
+
+use hyper::{
+    header::{HeaderName, HeaderValue, FORWARDED, HOST},
+    HeaderMap,
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+// Proxies in front of proxrs whose forwarding headers are believed
+pub fn trusted_proxies(conf: &Config) -> Vec<IpNet> {
+    check_err!(conf.get(TrustedProxies))
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| {
+            check_err!(proxy
+                .parse::<IpNet>()
+                .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!(
+                    "Invalid value for {}: {} is not an ip address or network",
+                    TrustedProxies, proxy
+                )))
+        })
+        .collect()
+}
+
+fn is_trusted(proxies: &[IpNet], ip: IpAddr) -> bool {
+    proxies.iter().any(|proxy| proxy.contains(&ip))
+}
+
+// Get the address of the client, looking through the trusted proxies the request passed
+pub fn client_ip(conf: &Config, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    let proxies = trusted_proxies(conf);
+    if !is_trusted(&proxies, peer) {
+        return peer;
+    }
+
+    // Every proxy appends the address it got the request from, so walk back until one isn't trusted
+    let mut client = peer;
+    for hop in forwarded_chain(headers).iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = *ip;
+                if !is_trusted(&proxies, *ip) {
+                    break;
+                }
+            }
+            // Hidden or garbled addresses end the chain
+            None => break,
+        }
+    }
+
+    client
+}
+
+// Set the X-Forwarded-* and Forwarded headers for the upstream, only keeping the ones sent by trusted proxies
+// Has to be called before the Host header is changed to the upstream
+pub fn set_forwarded_headers(conf: &Config, headers: &mut HeaderMap, peer: IpAddr) {
+    if !is_trusted(&trusted_proxies(conf), peer) {
+        for name in [
+            X_FORWARDED_FOR,
+            X_FORWARDED_PROTO,
+            X_FORWARDED_HOST,
+            FORWARDED,
+        ] {
+            headers.remove(name);
+        }
+    }
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(String::from);
+
+    // Add the peer to the chain of addresses
+    let forwarded_for = match joined(headers, X_FORWARDED_FOR) {
+        Some(chain) => format!("{}, {}", chain, peer),
+        None => peer.to_string(),
+    };
+    set(headers, X_FORWARDED_FOR, &forwarded_for);
+
+    // Keep the scheme and host the client used when a trusted proxy said so, proxrs itself only speaks http
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        set(headers, X_FORWARDED_PROTO, "http");
+    }
+    if let Some(host) = &host {
+        if !headers.contains_key(X_FORWARDED_HOST) {
+            set(headers, X_FORWARDED_HOST, host);
+        }
+    }
+
+    // RFC 7239 describes this hop as proxrs saw it
+    let mut element = format!("for={}", forwarded_node(peer));
+    if let Some(host) = &host {
+        element += &format!(";host={}", forwarded_value(host));
+    }
+    element += ";proto=http";
+    let forwarded = match joined(headers, FORWARDED) {
+        Some(elements) => format!("{}, {}", elements, element),
+        None => element,
+    };
+    set(headers, FORWARDED, &forwarded);
+}
+
+// The addresses from X-Forwarded-For, or from the for= parameters of Forwarded when there is none
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    if let Some(chain) = joined(headers, X_FORWARDED_FOR) {
+        return chain.split(',').map(parse_node).collect();
+    }
+
+    match joined(headers, FORWARDED) {
+        Some(elements) => elements
+            .split(',')
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// Parse an address like 192.0.2.1, 192.0.2.1:8080, "[2001:db8::1]:8080" or 2001:db8::1
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+// Ipv6 addresses have to be quoted and bracketed in Forwarded
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+// Quote values that aren't a plain token (like a host with a port)
+fn forwarded_value(value: &str) -> String {
+    let token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+
+    match token {
+        true => value.to_owned(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+// All values of a header as one comma separated list
+fn joined(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+
+    match values.is_empty() {
+        true => None,
+        false => Some(values.join(", ")),
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf() -> Config {
+        Config::for_tests(&[(TrustedProxies, "10.0.0.0/8, 127.0.0.1")])
+    }
+
+    type Values<'a> = &'a [(&'a HeaderName, &'static str)];
+
+    fn headers(values: Values) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn finds_the_client_behind_trusted_proxies() {
+        let cases: [(&str, Values, &str); 7] = [
+            // A client can't claim to be someone else
+            (
+                "203.0.113.5",
+                &[(&X_FORWARDED_FOR, "6.6.6.6")],
+                "203.0.113.5",
+            ),
+            ("203.0.113.5", &[(&FORWARDED, "for=6.6.6.6")], "203.0.113.5"),
+            ("10.0.0.2", &[], "10.0.0.2"),
+            (
+                "10.0.0.2",
+                &[(&X_FORWARDED_FOR, "198.51.100.7, 10.0.0.3")],
+                "198.51.100.7",
+            ),
+            // Only the address the first trusted proxy saw counts, not what the client added before it
+            (
+                "10.0.0.2",
+                &[
+                    (&X_FORWARDED_FOR, "6.6.6.6"),
+                    (&X_FORWARDED_FOR, "198.51.100.7, 127.0.0.1"),
+                ],
+                "198.51.100.7",
+            ),
+            (
+                "127.0.0.1",
+                &[(&FORWARDED, "for=6.6.6.6, for=\"[2001:db8::1]:8080\"")],
+                "2001:db8::1",
+            ),
+            (
+                "10.0.0.2",
+                &[(&X_FORWARDED_FOR, "198.51.100.7, unknown")],
+                "10.0.0.2",
+            ),
+        ];
+        for (peer, values, expected) in cases {
+            let client = client_ip(&conf(), &headers(values), ip(peer));
+            assert_eq!(client, ip(expected), "peer {} with {:?}", peer, values);
+        }
+    }
+
+    #[test]
+    fn replaces_the_headers_of_untrusted_peers() {
+        let mut headers = headers(&[
+            (&HOST, "app.local:3678"),
+            (&X_FORWARDED_FOR, "6.6.6.6"),
+            (&X_FORWARDED_PROTO, "https"),
+            (&X_FORWARDED_HOST, "evil.com"),
+            (&FORWARDED, "for=6.6.6.6;proto=https"),
+        ]);
+        set_forwarded_headers(&conf(), &mut headers, ip("203.0.113.5"));
+
+        assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.5");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "app.local:3678");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=203.0.113.5;host=\"app.local:3678\";proto=http"
+        );
+    }
+
+    #[test]
+    fn extends_the_headers_of_trusted_proxies() {
+        let mut headers = headers(&[
+            (&HOST, "app.local"),
+            (&X_FORWARDED_FOR, "198.51.100.7"),
+            (&X_FORWARDED_PROTO, "https"),
+            (&FORWARDED, "for=198.51.100.7;proto=https"),
+        ]);
+        set_forwarded_headers(&conf(), &mut headers, ip("10.0.0.2"));
+
+        assert_eq!(headers[X_FORWARDED_FOR], "198.51.100.7, 10.0.0.2");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_FORWARDED_HOST], "app.local");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=198.51.100.7;proto=https, for=10.0.0.2;host=app.local;proto=http"
+        );
+
+        let mut headers = HeaderMap::new();
+        set_forwarded_headers(&conf(), &mut headers, ip("::1"));
+        assert_eq!(headers[FORWARDED], "for=\"[::1]\";proto=http");
+    }
+}
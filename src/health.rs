@@ -0,0 +1,385 @@
+use crate::*;
+
+use chrono::{DateTime, Duration, Utc};
+use hashbrown::HashMap;
+use hyper::{header::USER_AGENT, Body, Request, StatusCode};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+
+// How a target is doing, as far as the checks and the requests to it tell
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    #[default]
+    Unknown,
+    Up,
+    Down,
+}
+
+#[derive(Clone, Debug, Default)]
+struct TargetHealth {
+    state: HealthState,
+    // Failed and passed checks or requests in a row
+    fails: u32,
+    passes: u32,
+    last_check: Option<DateTime<Utc>>,
+    down_since: Option<DateTime<Utc>>,
+    last_error: String,
+}
+
+// The health of a target of a route, for the admin page
+#[derive(Clone, Debug, Serialize)]
+pub struct TargetStatus {
+    pub target: String,
+    pub state: HealthState,
+    pub last_error: String,
+    pub down_since: String,
+}
+
+// How long to wait before trying again when the routes can't be loaded
+const RELOAD_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Health is only kept in memory, after a restart every target is tried again
+#[derive(Clone)]
+pub struct Health {
+    store: Arc<Mutex<HashMap<(i64, String), TargetHealth>>>,
+    // How long a target stays out after failing when its route has no active check
+    eject_time: Duration,
+    routes_changed: Arc<Notify>,
+}
+
+impl Health {
+    pub fn new(conf: &Config) -> Self {
+        let eject_time = check_err!(check_err!(conf.get(HealthEjectTime)).parse::<i64>());
+
+        Self {
+            store: Arc::default(),
+            eject_time: Duration::seconds(eject_time.max(1)),
+            routes_changed: Arc::default(),
+        }
+    }
+
+    // Get the targets of the route that may get requests
+    // Without an active check nothing would bring a target back, so it is tried again after the eject time
+    pub async fn available(&self, proxy: &Proxy) -> Vec<Target> {
+        let now = Utc::now();
+        let store = self.store.lock().await;
+
+        proxy
+            .targets
+            .iter()
+            .filter(|target| match store.get(&key(proxy, target)) {
+                Some(health) if health.state == HealthState::Down => {
+                    !proxy.health.is_active()
+                        && health
+                            .down_since
+                            .is_some_and(|since| since + self.eject_time <= now)
+                }
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Count a passed check or a good response from the target
+    // When the route has an active check, only that check brings a target back
+    pub async fn passed(&self, proxy: &Proxy, target: &Target, active: bool) {
+        let mut store = self.store.lock().await;
+        let health = store.entry(key(proxy, target)).or_default();
+        health.fails = 0;
+        health.passes = health.passes.saturating_add(1);
+
+        let up = match health.state {
+            HealthState::Up => false,
+            HealthState::Unknown => true,
+            HealthState::Down if proxy.health.is_active() => {
+                active && health.passes >= proxy.health.passes
+            }
+            HealthState::Down => true,
+        };
+        if up {
+            if health.state == HealthState::Down {
+                println!("Upstream {} of {} is up again", target, proxy.name);
+            }
+            health.state = HealthState::Up;
+            health.down_since = None;
+        }
+    }
+
+    // Count a failed check, connection error or server error of the target
+    pub async fn failed(&self, proxy: &Proxy, target: &Target, reason: String) {
+        let mut store = self.store.lock().await;
+        let health = store.entry(key(proxy, target)).or_default();
+        health.passes = 0;
+        health.fails = health.fails.saturating_add(1);
+
+        match health.state {
+            // A target that is tried again after the eject time goes straight back out
+            HealthState::Down if !proxy.health.is_active() => {
+                health.down_since = Some(Utc::now());
+            }
+            HealthState::Down => (),
+            _ if health.fails >= proxy.health.fails => {
+                println!("Upstream {} of {} is down: {}", target, proxy.name, reason);
+                health.state = HealthState::Down;
+                health.down_since = Some(Utc::now());
+            }
+            _ => (),
+        }
+        health.last_error = reason;
+    }
+
+    // Get the health of every target of the route
+    pub async fn status(&self, proxy: &Proxy) -> Vec<TargetStatus> {
+        let store = self.store.lock().await;
+
+        proxy
+            .targets
+            .iter()
+            .map(|target| {
+                let health = store.get(&key(proxy, target)).cloned().unwrap_or_default();
+                TargetStatus {
+                    target: target.to_string(),
+                    state: health.state,
+                    last_error: health.last_error,
+                    down_since: health
+                        .down_since
+                        .map(|since| since.to_rfc3339())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    // Tell the prober the routes were created, changed or deleted
+    pub fn routes_changed(&self) {
+        self.routes_changed.notify_one();
+    }
+
+    // Check the targets of the routes with an active check in the background
+    // The routes are only loaded again when they change, and the prober sleeps until the next check is due
+    pub fn spawn_prober(&self, db: Db, client: Client) {
+        let health = self.clone();
+
+        tokio::spawn(async move {
+            let mut routes = None;
+            loop {
+                if routes.is_none() {
+                    match db.get_proxies().await {
+                        Ok(proxies) => {
+                            health.forget_removed(&proxies).await;
+                            routes = Some(proxies);
+                        }
+                        Err(err) => {
+                            eprintln!("Failed to load the routes for health checks: {}", err);
+                            tokio::time::sleep(RELOAD_DELAY).await;
+                            continue;
+                        }
+                    }
+                }
+
+                let (due, next) = health.due(routes.as_deref().unwrap_or_default()).await;
+                for (proxy, target) in due {
+                    let (health, client) = (health.clone(), client.clone());
+                    tokio::spawn(async move { health.probe(&client, &proxy, &target).await });
+                }
+
+                // Without active checks there is nothing to do until the routes change
+                let next_check = async {
+                    match next {
+                        Some(next) => tokio::time::sleep(next).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = next_check => (),
+                    _ = health.routes_changed.notified() => routes = None,
+                }
+            }
+        });
+    }
+
+    // Forget the health of targets that are no longer configured
+    async fn forget_removed(&self, proxies: &[Proxy]) {
+        self.store.lock().await.retain(|(proxy_id, target), _| {
+            proxies.iter().any(|proxy| {
+                proxy.id == *proxy_id && proxy.targets.iter().any(|t| t.to_string() == *target)
+            })
+        });
+    }
+
+    // Get the targets whose check is due, and how long until the next one is
+    async fn due(&self, proxies: &[Proxy]) -> (Vec<(Proxy, Target)>, Option<std::time::Duration>) {
+        let now = Utc::now();
+        let mut store = self.store.lock().await;
+
+        let mut due = Vec::new();
+        let mut next: Option<DateTime<Utc>> = None;
+        for proxy in proxies {
+            if !proxy.is_enabled || !proxy.health.is_active() {
+                continue;
+            }
+            let interval = Duration::seconds(proxy.health.interval.into());
+            for target in &proxy.targets {
+                let health = store.entry(key(proxy, target)).or_default();
+                let check = match health.last_check {
+                    Some(last) if last + interval > now => last + interval,
+                    _ => {
+                        health.last_check = Some(now);
+                        due.push((proxy.clone(), target.clone()));
+                        now + interval
+                    }
+                };
+                next = Some(next.map_or(check, |next| next.min(check)));
+            }
+        }
+
+        let next = next.map(|next| (next - now).to_std().unwrap_or_default());
+        (due, next)
+    }
+
+    // Check a target once, giving up after the interval so checks of a target never overlap
+    async fn probe(&self, client: &Client, proxy: &Proxy, target: &Target) {
+        let req = Request::get(proxy.health_uri(target))
+            .header(USER_AGENT, "proxrs-health-check")
+            .body(Body::empty());
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => {
+                let reason = format!("invalid health check: {}", err);
+                return self.failed(proxy, target, reason).await;
+            }
+        };
+
+        let timeout = std::time::Duration::from_secs(proxy.health.interval.into());
+        let expected = StatusCode::from_u16(proxy.health.status).ok();
+        match tokio::time::timeout(timeout, client.request(req)).await {
+            Ok(Ok(res)) if Some(res.status()) == expected => self.passed(proxy, target, true).await,
+            Ok(Ok(res)) => {
+                let reason = format!("health check answered {}", res.status());
+                self.failed(proxy, target, reason).await
+            }
+            Ok(Err(err)) => self.failed(proxy, target, err.to_string()).await,
+            Err(_) => {
+                let reason = "health check timed out".to_owned();
+                self.failed(proxy, target, reason).await
+            }
+        }
+    }
+}
+
+fn key(proxy: &Proxy, target: &Target) -> (i64, String) {
+    (proxy.id, target.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(port: u16) -> Target {
+        Target {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    fn proxy(health_path: &str) -> Proxy {
+        Proxy {
+            id: 1,
+            name: "app.local".to_string(),
+            targets: vec![target(3000), target(3001)],
+            is_enabled: true,
+            health: HealthCheck {
+                path: health_path.to_string(),
+                ..HealthCheck::default()
+            },
+            ..Proxy::default()
+        }
+    }
+
+    fn health() -> Health {
+        Health::new(&Config::for_tests(&[]))
+    }
+
+    #[tokio::test]
+    async fn schedules_checks_by_interval() {
+        let health = health();
+        let proxies = [proxy("/healthz")];
+
+        let (due, next) = health.due(&proxies).await;
+        assert_eq!(due.len(), 2);
+        assert!(next.is_some_and(|next| next.as_secs() <= 10 && next.as_secs() >= 9));
+
+        let (due, next) = health.due(&proxies).await;
+        assert!(due.is_empty());
+        assert!(next.is_some_and(|next| next.as_secs() <= 10));
+
+        // Routes without an active check are never probed
+        let (due, next) = health.due(&[proxy("")]).await;
+        assert!(due.is_empty());
+        assert!(next.is_none());
+    }
+
+    #[tokio::test]
+    async fn ejects_after_failures_in_a_row() {
+        let health = health();
+        let proxy = proxy("");
+
+        for _ in 0..2 {
+            health
+                .failed(&proxy, &target(3001), "refused".to_string())
+                .await;
+        }
+        health.passed(&proxy, &target(3001), false).await;
+        for _ in 0..2 {
+            health
+                .failed(&proxy, &target(3001), "refused".to_string())
+                .await;
+        }
+        assert_eq!(health.available(&proxy).await.len(), 2);
+
+        health
+            .failed(&proxy, &target(3001), "refused".to_string())
+            .await;
+        assert_eq!(health.available(&proxy).await, [target(3000)]);
+    }
+
+    #[tokio::test]
+    async fn active_checks_bring_targets_back() {
+        let health = health();
+        let proxy = proxy("/healthz");
+
+        for _ in 0..3 {
+            health
+                .failed(&proxy, &target(3001), "refused".to_string())
+                .await;
+        }
+        assert_eq!(health.available(&proxy).await, [target(3000)]);
+
+        // A good response to a request doesn't count while the route has an active check
+        health.passed(&proxy, &target(3001), false).await;
+        health.passed(&proxy, &target(3001), false).await;
+        assert_eq!(health.available(&proxy).await, [target(3000)]);
+
+        health.passed(&proxy, &target(3001), true).await;
+        health.passed(&proxy, &target(3001), true).await;
+        assert_eq!(health.available(&proxy).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn forgets_removed_targets() {
+        let health = health();
+        let mut proxy = proxy("");
+        for _ in 0..3 {
+            health
+                .failed(&proxy, &target(3001), "refused".to_string())
+                .await;
+        }
+
+        proxy.targets.pop();
+        health.forget_removed(&[proxy.clone()]).await;
+        proxy.targets.push(target(3001));
+        assert_eq!(health.available(&proxy).await.len(), 2);
+    }
+}
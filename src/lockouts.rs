@@ -0,0 +1,295 @@
+use crate::*;
+
+use chrono::{DateTime, Duration, Utc};
+use hashbrown::HashMap;
+use serde::Serialize;
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+use tokio::sync::Mutex;
+
+// What failed logins are counted against
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl std::fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockoutKey::User(username) => write!(f, "user:{}", username),
+            LockoutKey::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+impl FromStr for LockoutKey {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Error> {
+        match key.split_once(':') {
+            Some(("user", username)) => Ok(LockoutKey::User(username.to_owned())),
+            Some(("ip", ip)) => ip
+                .parse()
+                .map(LockoutKey::Ip)
+                .map_err(|_| Error::BadRequest(format!("{} is not a valid ip address", ip))),
+            _ => Err(Error::BadRequest(format!(
+                "{} is not a valid lockout, expected user:<username> or ip:<address>",
+                key
+            ))),
+        }
+    }
+}
+
+// The failed logins of a username or ip address
+#[derive(Clone, Debug)]
+struct Failures {
+    count: u32,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+// A username or ip address that currently can't log in
+#[derive(Clone, Debug, Serialize)]
+pub struct Lockout {
+    pub key: String,
+    pub kind: &'static str,
+    pub name: String,
+    pub failures: u32,
+    pub locked_until: String,
+}
+
+// Failed logins are only kept in memory, a restart unlocks everyone
+#[derive(Clone, Default)]
+pub struct Lockouts {
+    store: Arc<Mutex<HashMap<LockoutKey, Failures>>>,
+}
+
+impl Lockouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Get how long the username or ip address is still locked out for
+    pub async fn locked(&self, username: &str, ip: IpAddr) -> Option<Duration> {
+        let now = Utc::now();
+        let store = self.store.lock().await;
+
+        [LockoutKey::User(username.to_owned()), LockoutKey::Ip(ip)]
+            .iter()
+            .filter_map(|key| store.get(key)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max()
+    }
+
+    // Count a failed login, locks the username or ip address out when it has failed too often
+    pub async fn failed(&self, username: &str, ip: IpAddr, conf: &Config) {
+        let settings = LockoutSettings::from_conf(conf);
+        let now = Utc::now();
+        let mut store = self.store.lock().await;
+
+        // Forget failures that are long enough ago
+        store.retain(|_, failures| !settings.forgotten(failures, now));
+
+        for (key, max_attempts) in [
+            (LockoutKey::User(username.to_owned()), settings.max_attempts),
+            (LockoutKey::Ip(ip), settings.max_attempts_ip),
+        ] {
+            let failures = store.entry(key).or_insert(Failures {
+                count: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            failures.count += 1;
+            failures.last_failure = now;
+
+            // Double the lockout for every failure past the limit
+            if failures.count >= max_attempts {
+                let doublings = (failures.count - max_attempts).min(16);
+                let lockout = (settings.lockout_time.saturating_mul(1 << doublings))
+                    .min(settings.max_lockout_time);
+                failures.locked_until = Some(now + Duration::seconds(lockout));
+            }
+        }
+    }
+
+    // Forget the failed logins of a username after it logged in
+    pub async fn succeeded(&self, username: &str) {
+        self.store
+            .lock()
+            .await
+            .remove(&LockoutKey::User(username.to_owned()));
+    }
+
+    // Get all usernames and ip addresses that are locked out right now
+    pub async fn all(&self) -> Vec<Lockout> {
+        let now = Utc::now();
+        let store = self.store.lock().await;
+
+        let mut lockouts = store
+            .iter()
+            .filter_map(|(key, failures)| {
+                let locked_until = failures.locked_until.filter(|until| *until > now)?;
+                let (kind, name) = match key {
+                    LockoutKey::User(username) => ("user", username.to_owned()),
+                    LockoutKey::Ip(ip) => ("ip", ip.to_string()),
+                };
+
+                Some(Lockout {
+                    key: key.to_string(),
+                    kind,
+                    name,
+                    failures: failures.count,
+                    locked_until: locked_until.to_rfc3339(),
+                })
+            })
+            .collect::<Vec<_>>();
+        lockouts.sort_by(|a, b| a.key.cmp(&b.key));
+
+        lockouts
+    }
+
+    // Unlock a username or ip address, returns if it had failed logins
+    pub async fn unlock(&self, key: &LockoutKey) -> bool {
+        self.store.lock().await.remove(key).is_some()
+    }
+}
+
+// The lockout thresholds from the config
+struct LockoutSettings {
+    max_attempts: u32,
+    max_attempts_ip: u32,
+    lockout_time: i64,
+    max_lockout_time: i64,
+}
+
+impl LockoutSettings {
+    fn from_conf(conf: &Config) -> Self {
+        Self {
+            max_attempts: check_err!(check_err!(conf.get(LoginMaxAttempts)).parse::<u32>()).max(1),
+            max_attempts_ip: check_err!(check_err!(conf.get(LoginMaxAttemptsIp)).parse::<u32>())
+                .max(1),
+            lockout_time: check_err!(check_err!(conf.get(LoginLockoutTime)).parse::<i64>()).max(1),
+            max_lockout_time: check_err!(check_err!(conf.get(LoginMaxLockoutTime)).parse::<i64>())
+                .max(1),
+        }
+    }
+
+    // Failures are forgotten once they are not locked and the longest lockout has passed since the last one
+    fn forgotten(&self, failures: &Failures, now: DateTime<Utc>) -> bool {
+        let unlocked = failures.locked_until.is_none_or(|until| until <= now);
+        unlocked && failures.last_failure + Duration::seconds(self.max_lockout_time) <= now
+    }
+}
+
+// Tell the user how long to wait, in whole minutes once it is more than a minute
+pub fn wait_message(wait: Duration) -> String {
+    let seconds = wait.num_seconds().max(1);
+    let wait = match seconds {
+        1 => "1 second".to_string(),
+        2..=59 => format!("{} seconds", seconds),
+        60 => "1 minute".to_string(),
+        _ => format!("{} minutes", (seconds + 59) / 60),
+    };
+
+    format!(
+        "Too many failed login attempts. Please wait {} before trying again.",
+        wait
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf() -> Config {
+        Config::for_tests(&[
+            (LoginMaxAttempts, "3"),
+            (LoginMaxAttemptsIp, "5"),
+            (LoginLockoutTime, "60"),
+            (LoginMaxLockoutTime, "300"),
+        ])
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 1, last])
+    }
+
+    // The lockout in whole seconds, rounded up from what is left of it
+    async fn locked_for(lockouts: &Lockouts, username: &str, ip: IpAddr) -> Option<i64> {
+        let wait = lockouts.locked(username, ip).await?;
+        Some((wait.num_milliseconds() + 999) / 1000)
+    }
+
+    #[tokio::test]
+    async fn locks_out_at_the_threshold() {
+        let (lockouts, conf) = (Lockouts::new(), conf());
+
+        for _ in 0..2 {
+            lockouts.failed("stan", ip(1), &conf).await;
+        }
+        assert_eq!(lockouts.locked("stan", ip(1)).await, None);
+
+        lockouts.failed("stan", ip(1), &conf).await;
+        assert_eq!(locked_for(&lockouts, "stan", ip(2)).await, Some(60));
+        assert_eq!(lockouts.locked("admin", ip(2)).await, None);
+
+        // The ip address has its own, higher threshold
+        assert_eq!(lockouts.locked("admin", ip(1)).await, None);
+        for username in ["admin", "root"] {
+            lockouts.failed(username, ip(1), &conf).await;
+        }
+        assert_eq!(locked_for(&lockouts, "admin", ip(1)).await, Some(60));
+    }
+
+    #[tokio::test]
+    async fn doubles_the_lockout_up_to_the_maximum() {
+        let (lockouts, conf) = (Lockouts::new(), conf());
+
+        let mut waits = Vec::new();
+        for i in 0..6 {
+            lockouts.failed("stan", ip(i), &conf).await;
+            waits.push(locked_for(&lockouts, "stan", ip(100)).await);
+        }
+        assert_eq!(
+            waits,
+            [None, None, Some(60), Some(120), Some(240), Some(300)]
+        );
+    }
+
+    #[tokio::test]
+    async fn logging_in_resets_the_username() {
+        let (lockouts, conf) = (Lockouts::new(), conf());
+
+        for username in ["stan", "stan", "stan", "admin", "root"] {
+            lockouts.failed(username, ip(1), &conf).await;
+        }
+        assert_eq!(lockouts.all().await.len(), 2);
+
+        // The failures of the ip address are still counted
+        lockouts.succeeded("stan").await;
+        assert_eq!(lockouts.locked("stan", ip(2)).await, None);
+        assert!(lockouts.locked("stan", ip(1)).await.is_some());
+
+        lockouts.failed("stan", ip(2), &conf).await;
+        assert_eq!(lockouts.locked("stan", ip(2)).await, None);
+    }
+
+    #[test]
+    fn rounds_waits_to_minutes() {
+        let cases = [
+            (0, "1 second"),
+            (45, "45 seconds"),
+            (60, "1 minute"),
+            (61, "2 minutes"),
+        ];
+        for (seconds, wait) in cases {
+            let message = wait_message(Duration::seconds(seconds));
+            assert!(
+                message.contains(&format!("wait {} before", wait)),
+                "{}",
+                message
+            );
+        }
+    }
+}
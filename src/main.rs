@@ -1,14 +1,24 @@
+mod balancer;
 mod conf;
 mod database;
 mod error;
+mod forwarded;
+mod health;
+mod lockouts;
+mod password;
 mod routes;
 mod state;
 mod tokens;
+mod totp;
+mod webauthn;
 
-use crate::{conf::*, database::*, error::*, routes::*, state::*, tokens::*};
+use crate::{
+    balancer::*, conf::*, database::*, error::*, forwarded::*, health::*, lockouts::*, routes::*,
+    state::*, tokens::*,
+};
 
 use axum::{
-    routing::{get, post},
+    routing::{any, get, post},
     Router, Server,
 };
 use hyper::{client::HttpConnector, Body};
@@ -22,6 +32,11 @@ async fn main() -> Result<(), Error> {
     // Get the config
     let conf = check_err!(init::conf());
 
+    // Check the cookie, header and proxy options now instead of on the first request
+    removal_cookie(&conf);
+    identity_header_names(&conf);
+    trusted_proxies(&conf);
+
     // Initialize the app state
     let state = AppState::new(&conf).await;
 
@@ -35,14 +50,49 @@ async fn main() -> Result<(), Error> {
     let login_route = special_route.to_owned() + "/login";
     let logout_route = special_route.to_owned() + "/logout";
     let admin_route = special_route.to_owned() + "/admin";
+    let setup_route = special_route.to_owned() + "/setup";
+    let invite_route = special_route.to_owned() + "/invite";
+    let two_factor_route = special_route.to_owned() + "/2fa";
+    let account_route = special_route.to_owned() + "/account";
+    let verify_route = special_route.to_owned() + "/verify";
+    let api_route = special_route.to_owned() + "/api/v1";
 
     // Create the app
     let app = Router::new()
         // Add the special routes
         .route(&login_route, get(login_page))
         .route(&login_route, post(login_req))
+        .route(&(login_route.to_owned() + "/passkey"), post(passkey_login))
+        .route(
+            &(login_route.to_owned() + "/passkey/options"),
+            post(passkey_login_options),
+        )
         .route(&logout_route, post(logout))
         .route(&admin_route, get(admin_page))
+        .route(&(admin_route.to_owned() + "/update"), post(admin_update))
+        .route(&(admin_route.to_owned() + "/delete"), post(admin_delete))
+        .route(&(admin_route.to_owned() + "/invite"), post(admin_invite))
+        .route(&(admin_route.to_owned() + "/unlock"), post(admin_unlock))
+        .route(&setup_route, get(setup_page))
+        .route(&setup_route, post(setup_req))
+        .route(&invite_route, get(invite_page))
+        .route(&invite_route, post(invite_req))
+        .route(&two_factor_route, get(two_factor_page))
+        .route(&two_factor_route, post(two_factor_req))
+        .route(&account_route, get(account_page))
+        .route(&account_route, post(account_req))
+        .route(
+            &(account_route.to_owned() + "/passkey"),
+            post(passkey_register),
+        )
+        .route(
+            &(account_route.to_owned() + "/passkey/options"),
+            post(passkey_register_options),
+        )
+        // Add the forward-auth endpoint for edge proxies
+        .route(&verify_route, any(verify))
+        // Add the json admin api
+        .nest(&api_route, api::router())
         // Add proxy route
         .fallback(proxy)
         // Add the app state
@@ -50,7 +100,7 @@ async fn main() -> Result<(), Error> {
 
     // Start the server
     let server = Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(async {
             tokio::signal::ctrl_c()
                 .await
@@ -0,0 +1,87 @@
+use crate::*;
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2,
+};
+use rand::rngs::OsRng;
+use sha2::Digest;
+
+// Outcome of checking a password against a stored hash
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verified {
+    Valid,
+    ValidNeedsRehash,
+    Invalid,
+}
+
+// Argon2id hash with the default parameters, checked against when a user doesn't exist so that
+// a login for an unknown username takes as long as one for a real user
+pub const DUMMY_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$2XtCjZsqMlTwUxuGNahcOQ$lJ+7uNqwRINgLOfObi8smCbgjNfibleUvJIOKNlp5gk";
+
+// Hash a password with Argon2id into the PHC string format
+// Argon2 is slow on purpose, so it runs on the blocking threads instead of holding up the async workers
+pub async fn hash(password: &str) -> Result<String, Error> {
+    let password = password.to_owned();
+    tokio::task::spawn_blocking(move || hash_blocking(&password)).await?
+}
+
+// Check a password against a stored hash, on the blocking threads like hash
+pub async fn verify(password: &str, stored: &str) -> Verified {
+    let (password, stored) = (password.to_owned(), stored.to_owned());
+    tokio::task::spawn_blocking(move || verify_blocking(&password, &stored))
+        .await
+        .unwrap_or(Verified::Invalid)
+}
+
+fn hash_blocking(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+
+    Ok(hash.to_string())
+}
+
+// Check a password against a stored hash (Argon2 PHC string or legacy unsalted SHA-256 hex)
+fn verify_blocking(password: &str, stored: &str) -> Verified {
+    // Legacy hashes are stored as bare hex, PHC strings always start with a '$'
+    if !stored.starts_with('$') {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(password);
+        let hashed = hex::encode(hasher.finalize());
+
+        return match constant_time_eq(hashed.as_bytes(), stored.as_bytes()) {
+            true => Verified::ValidNeedsRehash,
+            false => Verified::Invalid,
+        };
+    }
+
+    // Parse the PHC string
+    let parsed = match PasswordHash::new(stored) {
+        Ok(parsed) => parsed,
+        Err(_) => return Verified::Invalid,
+    };
+
+    // Verify the password
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Verified::Invalid;
+    }
+
+    // Hashes made with another Argon2 variant are upgraded to Argon2id
+    match parsed.algorithm == Algorithm::Argon2id.ident() {
+        true => Verified::Valid,
+        false => Verified::ValidNeedsRehash,
+    }
+}
+
+// Compare two byte strings without bailing out on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,289 @@
+use super::auth::login::get_query_param;
+use crate::*;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::extract::CookieJar;
+use chrono::Utc;
+use hyper::{header::SET_COOKIE, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use urlencoding::{decode, encode};
+
+// Send the account page to the user
+pub async fn account_page(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Response<Body>, Redirect> {
+    // Initialize variables
+    let (sessions, _, conf, _, _) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Only logged in users have an account page
+    let session = match sessions.get_from_cookie(&jar, &conf).await {
+        Some(session) => session,
+        None => return Err(Redirect::to(&format!("{}/login", special_route))),
+    };
+
+    // Get the msg and color from the query
+    let msg = get_query_param(&req, "msg").and_then(|msg| decode(&msg).ok().map(String::from));
+    let status = get_query_param(&req, "status");
+
+    Ok(render_account(&app_state, &jar, &session, msg, status, None).await)
+}
+
+#[derive(Deserialize)]
+struct AccountForm {
+    action: String,
+    #[serde(default)]
+    code: String,
+    passkey_id: Option<i64>,
+}
+
+// Turn two-factor authentication on or off, replace the recovery codes or remove a passkey
+pub async fn account_req(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> axum::response::Response {
+    // Initialize variables
+    let (sessions, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Only logged in users have an account page
+    let session = match sessions.get_from_cookie(&jar, &conf).await {
+        Some(session) => session,
+        None => return Redirect::to(&format!("{}/login", special_route)).into_response(),
+    };
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return account_redirect(&special_route, ERROR_MSG, "error"),
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return account_redirect(
+            &special_route,
+            "Your form has expired. Please reload the page and try again.",
+            "warning",
+        );
+    }
+    let form = match serde_urlencoded::from_bytes::<AccountForm>(&body) {
+        Ok(form) => form,
+        Err(_) => return account_redirect(&special_route, FORM_MSG, "error"),
+    };
+
+    match form.action.as_str() {
+        // Turn two-factor authentication on, once the user has shown the secret works
+        "enable" => {
+            let enrollments = app_state.totp_enrollments();
+            let secret = match enrollments.get(&session.user).await {
+                Some(secret) => secret,
+                None => {
+                    return account_redirect(
+                        &special_route,
+                        "Your setup has expired. Please scan the new qr code and try again.",
+                        "warning",
+                    )
+                }
+            };
+            let step = match totp::verify(&secret, &form.code, Utc::now().timestamp()) {
+                Some(step) => step,
+                None => return account_redirect(&special_route, CODE_MSG, "warning"),
+            };
+            let enabled = async {
+                if db.get_totp_secret(&session.user).await?.is_some() {
+                    return Ok(None);
+                }
+                db.set_totp_secret(&session.user, Some(&secret)).await?;
+                db.use_totp_step(&session.user, step).await?;
+                Ok::<_, Error>(Some(db.create_recovery_codes(&session.user).await?))
+            };
+            let enabled = enabled.await;
+            if let Ok(Some(_)) = enabled {
+                enrollments.finish(&session.user).await;
+            }
+            match enabled {
+                Ok(Some(codes)) => render_account(
+                    &app_state,
+                    &jar,
+                    &session,
+                    Some("Two-factor authentication is on.".to_string()),
+                    Some("success".to_string()),
+                    Some(codes),
+                )
+                .await
+                .into_response(),
+                Ok(None) => account_redirect(
+                    &special_route,
+                    "Two-factor authentication is already on.",
+                    "warning",
+                ),
+                Err(_) => account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+        }
+
+        // Turn two-factor authentication off, unless the admin requires it
+        "disable" => {
+            let required = async {
+                let policy = db.get_two_factor_policy().await?;
+                Ok::<_, Error>(policy.required(session.admin))
+            };
+            match required.await {
+                Ok(false) => (),
+                Ok(true) => {
+                    return account_redirect(
+                        &special_route,
+                        "Your admin requires two-factor authentication for your account.",
+                        "warning",
+                    )
+                }
+                Err(_) => return account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+            match db.validate_two_factor(&session.user, &form.code).await {
+                Ok(true) => (),
+                Ok(false) => return account_redirect(&special_route, CODE_MSG, "warning"),
+                Err(_) => return account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+            match db.set_totp_secret(&session.user, None).await {
+                Ok(_) => account_redirect(
+                    &special_route,
+                    "Two-factor authentication is off.",
+                    "success",
+                ),
+                Err(_) => account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+        }
+
+        // Replace the recovery codes
+        "recovery_codes" => {
+            match db.validate_two_factor(&session.user, &form.code).await {
+                Ok(true) => (),
+                Ok(false) => return account_redirect(&special_route, CODE_MSG, "warning"),
+                Err(_) => return account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+            match db.create_recovery_codes(&session.user).await {
+                Ok(codes) => render_account(
+                    &app_state,
+                    &jar,
+                    &session,
+                    Some("You have new recovery codes.".to_string()),
+                    Some("success".to_string()),
+                    Some(codes),
+                )
+                .await
+                .into_response(),
+                Err(_) => account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+        }
+
+        // Remove a passkey, the password keeps working
+        "delete_passkey" => {
+            let id = match form.passkey_id {
+                Some(id) => id,
+                None => return account_redirect(&special_route, FORM_MSG, "error"),
+            };
+            match db.delete_passkey(&session.user, id).await {
+                Ok(true) => {
+                    account_redirect(&special_route, "Your passkey has been removed.", "success")
+                }
+                Ok(false) => account_redirect(
+                    &special_route,
+                    "That passkey doesn't exist anymore.",
+                    "warning",
+                ),
+                Err(_) => account_redirect(&special_route, ERROR_MSG, "error"),
+            }
+        }
+
+        _ => account_redirect(&special_route, FORM_MSG, "error"),
+    }
+}
+
+const ERROR_MSG: &str = "Oops! Something went wrong. Please give it another try.";
+const FORM_MSG: &str =
+    "Oops! We couldn't process the information you provided. Can you please try again?";
+const CODE_MSG: &str = "That code is not correct. Please try again.";
+
+// Render the account page, new recovery codes are only shown right after they are made
+async fn render_account(
+    app_state: &AppState,
+    jar: &CookieJar,
+    session: &Session,
+    msg: Option<String>,
+    status: Option<String>,
+    recovery_codes: Option<Vec<String>>,
+) -> Response<Body> {
+    // Initialize variables
+    let (_, _, conf, tera, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Get the two-factor state and passkeys of the user
+    let data = async {
+        Ok::<_, Error>((
+            db.get_totp_secret(&session.user).await?.is_some(),
+            db.count_recovery_codes(&session.user).await?,
+            db.get_two_factor_policy().await?,
+            db.get_passkeys(&session.user).await?,
+        ))
+    };
+    let (two_factor, recovery_codes_left, policy, passkeys) = match data.await {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to load the account page: {}", err);
+            return error_page(
+                &tera,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Oops! Something went wrong while loading your account.",
+            );
+        }
+    };
+
+    // Create the context
+    let mut context = tera::Context::new();
+    context.insert("title", &format!("Account of {}", session.user));
+    context.insert("login_route", &(special_route.to_owned() + "/login"));
+    context.insert("account_route", &(special_route.to_owned() + "/account"));
+    context.insert("two_factor", &two_factor);
+    context.insert("two_factor_required", &policy.required(session.admin));
+    context.insert("recovery_codes_left", &recovery_codes_left);
+    context.insert("recovery_codes", &recovery_codes);
+    context.insert("passkeys", &passkeys);
+    context.insert("msg", &msg);
+    context.insert("status", &status);
+
+    // Add the csrf token for the forms
+    let (csrf_token, csrf_cookie) = csrf_token(&conf, jar);
+    context.insert("csrf_token", &csrf_token);
+
+    // Offer a new secret when two-factor authentication is off, it stays on the server until it is confirmed
+    if !two_factor {
+        let secret = app_state.totp_enrollments().start(&session.user).await;
+        let uri = totp::otpauth_uri("proxrs", &session.user, &secret);
+        context.insert("qr_code", &totp::qr_svg(&uri));
+        context.insert("secret", &secret);
+    }
+
+    // Render the account page
+    let account_page = check_err!(tera.render("account.tera.html", &context));
+
+    // Send the account page
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = csrf_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    res.body(Body::from(account_page)).unwrap()
+}
+
+// Send the user back to the account page with a message
+fn account_redirect(special_route: &str, msg: &str, status: &str) -> axum::response::Response {
+    Redirect::to(&format!(
+        "{}/account?msg={}&status={}",
+        special_route,
+        encode(msg),
+        status
+    ))
+    .into_response()
+}
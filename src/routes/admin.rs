@@ -1,77 +1,653 @@
+use super::auth::login::get_query_param;
 use crate::*;
 
-use axum::{extract::State, response::Response};
+use axum::{extract::State, response::Redirect};
 use axum_extra::extract::CookieJar;
-use hyper::{Request, StatusCode};
-use serde::Serialize;
+use hyper::{header::SET_COOKIE, Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use urlencoding::{decode, encode};
 
+// Send the admin page to the user
+pub async fn admin_page(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Response<Body>, Redirect> {
+    // Initialize variables
+    let (sessions, _, conf, tera, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+    let admin_route = special_route.to_owned() + "/admin";
+
+    // Only admins can see the admin page
+    let session = match sessions.get_from_cookie(&jar, &conf).await {
+        Some(session) => session,
+        None => return Err(Redirect::to(&format!("{}/login", special_route))),
+    };
+    if !session.admin {
+        return Ok(error_page(
+            &tera,
+            StatusCode::FORBIDDEN,
+            "You need to be an admin to see this page.",
+        ));
+    }
+
+    // Get the users, invites, proxy routes and settings
+    let data = async {
+        Ok::<_, Error>((
+            db.get_users().await?,
+            db.get_invites().await?,
+            db.get_proxies().await?,
+            db.get_two_factor_policy().await?,
+        ))
+    };
+    let (users, invites, proxies, two_factor_policy) = match data.await {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to load the admin page: {}", err);
+            return Ok(error_page(
+                &tera,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Oops! Something went wrong while loading the admin page.",
+            ));
+        }
+    };
+
+    // Create the context
+    let mut context = tera::Context::new();
+    context.insert("title", "Admin");
+    context.insert("current_user", &session.user);
+
+    // Add the csrf token for the forms
+    let (csrf_token, csrf_cookie) = csrf_token(&conf, &jar);
+    context.insert("csrf_token", &csrf_token);
+
+    // Generate the routes
+    context.insert("login_route", &(special_route.to_owned() + "/login"));
+    context.insert(
+        "invite_accept_route",
+        &(special_route.to_owned() + "/invite"),
+    );
+    context.insert("update_route", &(admin_route.to_owned() + "/update"));
+    context.insert("delete_route", &(admin_route.to_owned() + "/delete"));
+    context.insert("invite_route", &(admin_route.to_owned() + "/invite"));
+    context.insert("unlock_route", &(admin_route.to_owned() + "/unlock"));
+
+    // Add the data
+    context.insert("users", &users);
+    context.insert("invites", &invites);
+    context.insert("lockouts", &app_state.lockouts().all().await);
+    let health = app_state.health();
+    let mut proxy_rows = Vec::new();
+    for proxy in proxies {
+        let upstreams = health.status(&proxy).await;
+        proxy_rows.push(ProxyRow { proxy, upstreams });
+    }
+    context.insert("proxies", &proxy_rows);
+    context.insert("two_factor_policy", two_factor_policy.as_str());
+    context.insert(
+        "balances",
+        &Balance::ALL
+            .iter()
+            .map(|balance| (balance.as_str(), balance.label()))
+            .collect::<Vec<_>>(),
+    );
+
+    // Get the msg and color from the query
+    if let Some(msg) = get_query_param(&req, "msg") {
+        if let Ok(msg) = decode(&msg) {
+            context.insert("msg", &msg);
+        }
+    }
+    if let Some(status) = get_query_param(&req, "status") {
+        context.insert("status", &status);
+    }
+
+    // Render the admin page
+    let admin_page = check_err!(tera.render("admin.tera.html", &context));
+
+    // Send the admin page
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = csrf_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    Ok(res.body(Body::from(admin_page)).unwrap())
+}
+
+// A proxy route with the health of its targets, for the admin page
 #[derive(Serialize)]
-struct User {
-    username: String,
+struct ProxyRow {
+    #[serde(flatten)]
+    proxy: Proxy,
+    upstreams: Vec<TargetStatus>,
+}
+
+#[derive(Deserialize)]
+struct UserForm {
+    user_id: i64,
     role: String,
-    id: i64,
+    groups: Option<String>,
+    password: Option<String>,
+    reset_two_factor: Option<String>,
 }
 
-#[derive(Serialize)]
-struct Proxy {
+#[derive(Deserialize)]
+struct ProxyForm {
+    proxy_id: Option<String>,
     name: String,
-    ip: String,
-    port: i64,
-    id: i64,
+    targets: String,
+    balance: String,
+    path_prefix: String,
+    strip_prefix: Option<String>,
+    is_enabled: Option<String>,
+    rewrite_body: Option<String>,
+    #[serde(default)]
+    rewrite_patterns: String,
+    #[serde(default)]
+    health_path: String,
+    #[serde(default)]
+    health_status: String,
+    #[serde(default)]
+    health_interval: String,
+    #[serde(default)]
+    health_fails: String,
+    #[serde(default)]
+    health_passes: String,
 }
 
-// Send the login page to the user
-pub async fn admin_page(
+// Update a user or create/update a proxy route
+pub async fn admin_update(
     State(app_state): State<AppState>,
-    _jar: CookieJar,
-    _req: Request<Body>,
-) -> Response<Body> {
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Redirect {
     // Initialize variables
-    let (_sessions, _, _conf, tera, _) = app_state.extract();
+    let (mut sessions, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
 
-    // Create the context
-    let mut context = tera::Context::new();
-    context.insert("title", "Admin");
+    // Only admins can make changes
+    let session = match admin_session(&sessions, &jar, &conf).await {
+        Ok(session) => session,
+        Err(redirect) => return redirect,
+    };
 
-    context.insert("update_route", "update");
-    context.insert("delete_route", "delete");
-    context.insert("invite_route", "invite");
-    let users = vec![
-        User {
-            username: "user1".to_string(),
-            role: "admin".to_string(),
-            id: 1,
-        },
-        User {
-            username: "user2".to_string(),
-            role: "user".to_string(),
-            id: 2,
-        },
-    ];
-    context.insert("users", &users);
-    let proxies = vec![
-        Proxy {
-            name: "proxy1".to_string(),
-            ip: "127.0.0.1".to_string(),
-            port: 8080,
-            id: 1,
-        },
-        Proxy {
-            name: "proxy2".to_string(),
-            ip: "739.293.293.293".to_string(),
-            port: 8080,
-            id: 2,
+    // Get data from the request
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", ""),
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return admin_redirect(&special_route, CSRF_MSG, "warning", "");
+    }
+    let fields = serde_urlencoded::from_bytes::<HashMap<String, String>>(&body).unwrap_or_default();
+
+    // Change who has to use two-factor authentication
+    if let Some(policy) = fields.get("two_factor_policy") {
+        let policy = match policy.parse::<TwoFactorPolicy>() {
+            Ok(policy) => policy,
+            Err(_) => return admin_redirect(&special_route, FORM_MSG, "error", "users"),
+        };
+
+        return match db.set_two_factor_policy(policy).await {
+            Ok(_) => admin_redirect(
+                &special_route,
+                "The two-factor authentication policy has been saved.",
+                "success",
+                "users",
+            ),
+            Err(_) => admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+        };
+    }
+
+    // Update a user
+    if fields.contains_key("user_id") {
+        let form = match serde_urlencoded::from_bytes::<UserForm>(&body) {
+            Ok(form) => form,
+            Err(_) => return admin_redirect(&special_route, FORM_MSG, "error", "users"),
+        };
+
+        // Get the user
+        let user = match db.get_user(form.user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                return admin_redirect(
+                    &special_route,
+                    "That user doesn't exist.",
+                    "warning",
+                    "users",
+                )
+            }
+            Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+        };
+
+        // Admins can't take away their own admin rights
+        let admin = form.role == "admin";
+        if user.username == session.user && !admin {
+            return admin_redirect(
+                &special_route,
+                "You can't remove your own admin rights.",
+                "warning",
+                "users",
+            );
+        }
+
+        // Check the groups when they were sent
+        let groups = match form
+            .groups
+            .as_deref()
+            .map(|groups| parse_groups(groups.split(',')))
+        {
+            Some(Ok(groups)) => Some(groups).filter(|groups| *groups != user.groups),
+            Some(Err(Error::BadRequest(msg))) => {
+                return admin_redirect(&special_route, &msg, "warning", "users")
+            }
+            Some(Err(_)) => return admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+            None => None,
+        };
+
+        // Update the role
+        if admin != user.admin && db.set_admin(user.id, admin).await.is_err() {
+            return admin_redirect(&special_route, ERROR_MSG, "error", "users");
+        }
+
+        // Update the groups
+        if let Some(groups) = &groups {
+            if db.set_groups(user.id, groups).await.is_err() {
+                return admin_redirect(&special_route, ERROR_MSG, "error", "users");
+            }
+        }
+
+        // Update the password when a new one was given
+        let password = form.password.unwrap_or_default();
+        if !password.is_empty() && db.set_password(user.id, &password).await.is_err() {
+            return admin_redirect(&special_route, ERROR_MSG, "error", "users");
+        }
+
+        // Turn two-factor authentication off for users who lost their authenticator
+        if form.reset_two_factor.is_some()
+            && user.two_factor
+            && db.set_totp_secret(&user.username, None).await.is_err()
+        {
+            return admin_redirect(&special_route, ERROR_MSG, "error", "users");
+        }
+
+        // Log the user out everywhere so the changes take effect
+        if user.username != session.user
+            && (admin != user.admin || groups.is_some() || !password.is_empty())
+            && sessions.delete_user(&user.username).await.is_err()
+        {
+            return admin_redirect(&special_route, ERROR_MSG, "error", "users");
+        }
+
+        return admin_redirect(
+            &special_route,
+            &format!("The user {} has been updated.", user.username),
+            "success",
+            "users",
+        );
+    }
+
+    // Create or update a proxy route
+    let form = match serde_urlencoded::from_bytes::<ProxyForm>(&body) {
+        Ok(form) => form,
+        Err(_) => return admin_redirect(&special_route, FORM_MSG, "error", "proxies"),
+    };
+    let proxy = match proxy_from_form(form, &special_route) {
+        Ok(proxy) => proxy,
+        Err(msg) => return admin_redirect(&special_route, msg, "warning", "proxies"),
+    };
+
+    // Save the proxy route
+    let result = match proxy.id {
+        0 => db.create_proxy(&proxy).await.map(|_| "created"),
+        _ => match db.get_proxy(proxy.id).await {
+            Ok(Some(_)) => db.update_proxy(&proxy).await.map(|_| "updated"),
+            Ok(None) => {
+                return admin_redirect(
+                    &special_route,
+                    "That route doesn't exist.",
+                    "warning",
+                    "proxies",
+                )
+            }
+            Err(err) => Err(err),
         },
-    ];
-    context.insert("proxies", &proxies);
-
-    // Render the login page
-    dbg!(&context);
-    let login_page = check_err!(tera.render("admin.tera.html", &context));
-
-    // Send the login page
-    Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::from(login_page))
-        .unwrap()
+    };
+    if result.is_ok() {
+        app_state.health().routes_changed();
+    }
+    match result {
+        Ok(action) => admin_redirect(
+            &special_route,
+            &format!(
+                "The route {}{} has been {}.",
+                proxy.name,
+                proxy.prefix(),
+                action
+            ),
+            "success",
+            "proxies",
+        ),
+        Err(_) => admin_redirect(&special_route, ERROR_MSG, "error", "proxies"),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteForm {
+    user_id: Option<i64>,
+    proxy_id: Option<i64>,
+    invite_token: Option<String>,
+}
+
+// Delete a user, invite or proxy route
+pub async fn admin_delete(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Redirect {
+    // Initialize variables
+    let (mut sessions, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Only admins can make changes
+    let session = match admin_session(&sessions, &jar, &conf).await {
+        Ok(session) => session,
+        Err(redirect) => return redirect,
+    };
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", ""),
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return admin_redirect(&special_route, CSRF_MSG, "warning", "");
+    }
+    let form = match serde_urlencoded::from_bytes::<DeleteForm>(&body) {
+        Ok(form) => form,
+        Err(_) => return admin_redirect(&special_route, FORM_MSG, "error", ""),
+    };
+
+    // Delete a user
+    if let Some(user_id) = form.user_id {
+        let user = match db.get_user(user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                return admin_redirect(
+                    &special_route,
+                    "That user doesn't exist.",
+                    "warning",
+                    "users",
+                )
+            }
+            Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+        };
+
+        // Admins can't delete themselves
+        if user.username == session.user {
+            return admin_redirect(
+                &special_route,
+                "You can't delete your own account.",
+                "warning",
+                "users",
+            );
+        }
+
+        // Delete the user and log them out everywhere
+        if db.delete_user(user.id).await.is_err()
+            || sessions.delete_user(&user.username).await.is_err()
+        {
+            return admin_redirect(&special_route, ERROR_MSG, "error", "users");
+        }
+
+        return admin_redirect(
+            &special_route,
+            &format!("The user {} has been deleted.", user.username),
+            "success",
+            "users",
+        );
+    }
+
+    // Revoke an invite
+    if let Some(token) = form.invite_token {
+        return match db.delete_invite(&token).await {
+            Ok(_) => admin_redirect(
+                &special_route,
+                "The invite has been revoked.",
+                "success",
+                "users",
+            ),
+            Err(_) => admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+        };
+    }
+
+    // Delete a proxy route
+    if let Some(proxy_id) = form.proxy_id {
+        return match db.delete_proxy(proxy_id).await {
+            Ok(_) => {
+                app_state.health().routes_changed();
+                admin_redirect(
+                    &special_route,
+                    "The route has been deleted.",
+                    "success",
+                    "proxies",
+                )
+            }
+            Err(_) => admin_redirect(&special_route, ERROR_MSG, "error", "proxies"),
+        };
+    }
+
+    admin_redirect(&special_route, FORM_MSG, "error", "")
+}
+
+#[derive(Deserialize)]
+struct InviteForm {
+    username: String,
+    role: String,
+}
+
+// Invite a new user
+pub async fn admin_invite(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Redirect {
+    // Initialize variables
+    let (sessions, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Only admins can make changes
+    if let Err(redirect) = admin_session(&sessions, &jar, &conf).await {
+        return redirect;
+    }
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return admin_redirect(&special_route, CSRF_MSG, "warning", "users");
+    }
+    let form = match serde_urlencoded::from_bytes::<InviteForm>(&body) {
+        Ok(form) => form,
+        Err(_) => return admin_redirect(&special_route, FORM_MSG, "error", "users"),
+    };
+
+    // Validate the username
+    let username = form.username.trim();
+    if username.is_empty() {
+        return admin_redirect(
+            &special_route,
+            "Please enter a username.",
+            "warning",
+            "users",
+        );
+    }
+    match db.username_exists(username).await {
+        Ok(false) => (),
+        Ok(true) => {
+            return admin_redirect(
+                &special_route,
+                &format!("The username {} is already taken.", username),
+                "warning",
+                "users",
+            )
+        }
+        Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+    }
+
+    // Create the invite
+    match db.create_invite(username, form.role == "admin").await {
+        Ok(_) => admin_redirect(
+            &special_route,
+            &format!(
+                "{} has been invited, send them the invite link below.",
+                username
+            ),
+            "success",
+            "users",
+        ),
+        Err(_) => admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+    }
+}
+
+#[derive(Deserialize)]
+struct UnlockForm {
+    lockout: String,
+}
+
+// Unlock a username or ip address that is locked out after failed logins
+pub async fn admin_unlock(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Redirect {
+    // Initialize variables
+    let (sessions, _, conf, _, _) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Only admins can make changes
+    if let Err(redirect) = admin_session(&sessions, &jar, &conf).await {
+        return redirect;
+    }
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return admin_redirect(&special_route, ERROR_MSG, "error", "users"),
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return admin_redirect(&special_route, CSRF_MSG, "warning", "users");
+    }
+    let key = match serde_urlencoded::from_bytes::<UnlockForm>(&body)
+        .ok()
+        .and_then(|form| form.lockout.parse::<LockoutKey>().ok())
+    {
+        Some(key) => key,
+        None => return admin_redirect(&special_route, FORM_MSG, "error", "users"),
+    };
+
+    // Unlock it
+    app_state.lockouts().unlock(&key).await;
+    let name = match &key {
+        LockoutKey::User(username) => username.to_owned(),
+        LockoutKey::Ip(ip) => ip.to_string(),
+    };
+    admin_redirect(
+        &special_route,
+        &format!("{} can log in again.", name),
+        "success",
+        "users",
+    )
+}
+
+const ERROR_MSG: &str = "Oops! Something went wrong. Please give it another try.";
+const FORM_MSG: &str =
+    "Oops! We couldn't process the information you provided. Can you please try again?";
+const CSRF_MSG: &str = "Your form has expired. Please reload the page and try again.";
+
+// Get the session of the admin making a change, or where to send everyone else
+async fn admin_session(
+    sessions: &Sessions,
+    jar: &CookieJar,
+    conf: &Config,
+) -> Result<Session, Redirect> {
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    match sessions.get_from_cookie(jar, conf).await {
+        Some(session) if session.admin => Ok(session),
+        _ => Err(Redirect::to(&format!(
+            "{}/login?msg={}&status=warning",
+            special_route,
+            encode("You need to be logged in as an admin to do that.")
+        ))),
+    }
+}
+
+// Send the admin back to the admin page with a message
+fn admin_redirect(special_route: &str, msg: &str, status: &str, tab: &str) -> Redirect {
+    Redirect::to(&format!(
+        "{}/admin?msg={}&status={}#{}",
+        special_route,
+        encode(msg),
+        status,
+        tab
+    ))
+}
+
+// Build and validate a proxy route from the form
+fn proxy_from_form(form: ProxyForm, special_route: &str) -> Result<Proxy, &'static str> {
+    // Parse the id, an empty id means a new route
+    let id = match form.proxy_id.as_deref().map(str::trim) {
+        None | Some("") => 0,
+        Some(id) => id.parse::<i64>().map_err(|_| "That route doesn't exist.")?,
+    };
+
+    // Parse the upstreams and how to balance between them
+    let targets = form
+        .targets
+        .split(',')
+        .filter(|target| !target.trim().is_empty())
+        .map(str::parse::<Target>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let balance = form
+        .balance
+        .parse::<Balance>()
+        .map_err(|_| "Please pick a balancing strategy.")?;
+
+    // Parse the health check, empty numbers keep their defaults
+    let defaults = HealthCheck::default();
+    let number = |value: &str, default: u32| match value.trim() {
+        "" => Ok(default),
+        value => value
+            .parse::<u32>()
+            .map_err(|_| "Please enter whole numbers for the health check."),
+    };
+    let health = HealthCheck {
+        path: form.health_path,
+        status: number(&form.health_status, defaults.status.into())?
+            .try_into()
+            .map_err(|_| "Please enter a health check status between 100 and 599.")?,
+        interval: number(&form.health_interval, defaults.interval)?,
+        fails: number(&form.health_fails, defaults.fails)?,
+        passes: number(&form.health_passes, defaults.passes)?,
+    };
+
+    // Create and validate the proxy route
+    let mut proxy = Proxy {
+        id,
+        name: form.name,
+        targets,
+        balance,
+        path_prefix: form.path_prefix,
+        strip_prefix: form.strip_prefix.is_some(),
+        is_enabled: form.is_enabled.is_some(),
+        rewrite_body: form.rewrite_body.is_some(),
+        rewrite_patterns: form.rewrite_patterns,
+        health,
+    };
+    proxy.validate(special_route)?;
+
+    Ok(proxy)
 }
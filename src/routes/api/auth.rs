@@ -0,0 +1,78 @@
+use crate::*;
+
+use axum::{async_trait, extract::FromRequestParts};
+use axum_extra::extract::CookieJar;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    http::request::Parts,
+    Method,
+};
+
+// The admin making an api request, either logged in or using an api token
+pub struct ApiAdmin {
+    pub name: String,
+    pub session: Option<Session>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ApiAdmin {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Error> {
+        // Initialize variables
+        let (sessions, _, conf, _, db) = state.extract();
+
+        // Use the api token when one is given
+        if let Some(header) = parts.headers.get(AUTHORIZATION) {
+            let token = header
+                .to_str()
+                .ok()
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .ok_or_else(|| Error::Unauthorized("Expected a bearer token".to_string()))?;
+
+            return match db.validate_api_token(token.trim()).await? {
+                Some(api_token) => Ok(Self {
+                    name: format!("token:{}", api_token.name),
+                    session: None,
+                }),
+                None => Err(Error::Unauthorized("Invalid api token".to_string())),
+            };
+        }
+
+        // Other sites can make browsers send plain form posts with the session cookie,
+        // a json body can't be sent cross-site without a cors preflight
+        let json = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if parts.method == Method::POST && !json {
+            return Err(Error::Forbidden(
+                "Requests using the session cookie must send application/json".to_string(),
+            ));
+        }
+
+        // Fall back to the session cookie
+        let jar = CookieJar::from_headers(&parts.headers);
+        match sessions.get_from_cookie(&jar, &conf).await {
+            Some(session) if session.admin => Ok(Self {
+                name: session.user.clone(),
+                session: Some(session),
+            }),
+            Some(_) => Err(Error::Forbidden("Admin rights are required".to_string())),
+            None => Err(Error::Unauthorized(
+                "Log in as an admin or send an api token".to_string(),
+            )),
+        }
+    }
+}
+
+impl ApiAdmin {
+    // Check if the request is made by the given user
+    pub fn is_user(&self, username: &str) -> bool {
+        match &self.session {
+            Some(session) => session.user == username,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use super::*;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+
+// List the usernames and ip addresses that are locked out
+pub async fn list(State(app_state): State<AppState>, _: ApiAdmin) -> Json<Vec<Lockout>> {
+    Json(app_state.lockouts().all().await)
+}
+
+// Unlock a username or ip address
+pub async fn delete(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(key): Path<String>,
+) -> Result<StatusCode, Error> {
+    let key = key.parse::<LockoutKey>()?;
+
+    match app_state.lockouts().unlock(&key).await {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(Error::NotFound(format!("{} has no failed logins", key))),
+    }
+}
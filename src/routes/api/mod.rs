@@ -0,0 +1,58 @@
+pub use auth::ApiAdmin;
+
+use crate::*;
+
+use axum::{
+    routing::{delete, get},
+    Router,
+};
+use serde::de::DeserializeOwned;
+
+mod auth;
+mod lockouts;
+mod proxies;
+mod sessions;
+mod settings;
+mod tokens;
+mod users;
+
+// Build the router of the json admin api
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users", get(users::list).post(users::create))
+        .route(
+            "/users/:id",
+            get(users::get).patch(users::update).delete(users::delete),
+        )
+        .route("/routes", get(proxies::list).post(proxies::create))
+        .route(
+            "/routes/:id",
+            get(proxies::get)
+                .put(proxies::update)
+                .delete(proxies::delete),
+        )
+        .route("/sessions", get(sessions::list))
+        .route("/sessions/:id", delete(sessions::delete))
+        .route("/tokens", get(tokens::list).post(tokens::create))
+        .route("/tokens/:id", delete(tokens::delete))
+        .route("/lockouts", get(lockouts::list))
+        .route("/lockouts/:key", delete(lockouts::delete))
+        .route("/settings", get(settings::get).patch(settings::update))
+        .fallback(not_found)
+}
+
+// Answer unknown api endpoints with a json error
+async fn not_found() -> Error {
+    Error::NotFound("No such api endpoint".to_string())
+}
+
+// Parse a json request body
+pub(crate) fn parse_json<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|err| Error::BadRequest(err.to_string()))
+}
+
+// Parse the id from the path
+fn parse_id(id: &str) -> Result<i64, Error> {
+    id.parse()
+        .map_err(|_| Error::BadRequest(format!("{} is not a valid id", id)))
+}
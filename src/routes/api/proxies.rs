@@ -0,0 +1,147 @@
+use super::*;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ProxyData {
+    name: String,
+    #[serde(default)]
+    targets: Vec<Target>,
+    // A single upstream can also be given without a list of targets
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(default)]
+    balance: Balance,
+    #[serde(default)]
+    path_prefix: String,
+    #[serde(default)]
+    strip_prefix: bool,
+    #[serde(default = "enabled_by_default")]
+    is_enabled: bool,
+    #[serde(default)]
+    rewrite_body: bool,
+    #[serde(default)]
+    rewrite_patterns: String,
+    #[serde(default)]
+    health: HealthCheck,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl ProxyData {
+    // Build and validate a proxy route from the request
+    fn into_proxy(self, id: i64, conf: &Config) -> Result<Proxy, Error> {
+        let special_route = check_err!(conf.get(SpecialRoute));
+        let targets = match (self.targets.is_empty(), self.host, self.port) {
+            (true, Some(host), Some(port)) => vec![Target { host, port }],
+            (false, None, None) => self.targets,
+            _ => {
+                return Err(Error::BadRequest(
+                    "Give either a list of targets, or a host and port".to_string(),
+                ))
+            }
+        };
+
+        let mut proxy = Proxy {
+            id,
+            name: self.name,
+            targets,
+            balance: self.balance,
+            path_prefix: self.path_prefix,
+            strip_prefix: self.strip_prefix,
+            is_enabled: self.is_enabled,
+            rewrite_body: self.rewrite_body,
+            rewrite_patterns: self.rewrite_patterns,
+            health: self.health,
+        };
+        proxy
+            .validate(&special_route)
+            .map_err(|msg| Error::BadRequest(msg.to_string()))?;
+
+        Ok(proxy)
+    }
+}
+
+// List all proxy routes
+pub async fn list(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+) -> Result<Json<Vec<Proxy>>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+
+    Ok(Json(db.get_proxies().await?))
+}
+
+// Create a proxy route
+pub async fn create(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Proxy>), Error> {
+    let (_, _, conf, _, db) = app_state.extract();
+    let proxy = parse_json::<ProxyData>(&body)?.into_proxy(0, &conf)?;
+
+    // Create the proxy route
+    let id = db.create_proxy(&proxy).await?;
+    app_state.health().routes_changed();
+
+    Ok((StatusCode::CREATED, Json(get_proxy(&db, id).await?)))
+}
+
+// Get a proxy route
+pub async fn get(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(id): Path<String>,
+) -> Result<Json<Proxy>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+
+    Ok(Json(get_proxy(&db, parse_id(&id)?).await?))
+}
+
+// Replace a proxy route
+pub async fn update(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<Proxy>, Error> {
+    let (_, _, conf, _, db) = app_state.extract();
+    let id = get_proxy(&db, parse_id(&id)?).await?.id;
+    let proxy = parse_json::<ProxyData>(&body)?.into_proxy(id, &conf)?;
+
+    // Update the proxy route
+    db.update_proxy(&proxy).await?;
+    app_state.health().routes_changed();
+
+    Ok(Json(get_proxy(&db, id).await?))
+}
+
+// Delete a proxy route
+pub async fn delete(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(id): Path<String>,
+) -> Result<StatusCode, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+    let proxy = get_proxy(&db, parse_id(&id)?).await?;
+
+    db.delete_proxy(proxy.id).await?;
+    app_state.health().routes_changed();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_proxy(db: &Db, id: i64) -> Result<Proxy, Error> {
+    db.get_proxy(id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("There is no route with id {}", id)))
+}
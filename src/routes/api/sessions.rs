@@ -0,0 +1,59 @@
+use super::*;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Serialize;
+
+// A session as shown by the api (without its token)
+#[derive(Serialize)]
+pub struct SessionInfo {
+    id: String,
+    user: String,
+    admin: bool,
+    expire_time: String,
+}
+
+impl From<Session> for SessionInfo {
+    fn from(session: Session) -> Self {
+        Self {
+            expire_time: session.expire_time().to_rfc3339(),
+            id: session.id,
+            user: session.user,
+            admin: session.admin,
+        }
+    }
+}
+
+// List all active sessions
+pub async fn list(State(app_state): State<AppState>, _: ApiAdmin) -> Json<Vec<SessionInfo>> {
+    let (sessions, _, _, _, _) = app_state.extract();
+
+    Json(
+        sessions
+            .all()
+            .await
+            .into_iter()
+            .map(SessionInfo::from)
+            .collect(),
+    )
+}
+
+// Revoke a session
+pub async fn delete(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(id): Path<String>,
+) -> Result<StatusCode, Error> {
+    let (mut sessions, _, _, _, _) = app_state.extract();
+
+    match sessions.delete_by_id(&id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(Error::NotFound(format!(
+            "There is no session with id {}",
+            id
+        ))),
+    }
+}
@@ -0,0 +1,39 @@
+use super::*;
+
+use axum::{body::Bytes, extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct Settings {
+    two_factor_policy: TwoFactorPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsUpdate {
+    two_factor_policy: Option<TwoFactorPolicy>,
+}
+
+// Get the settings
+pub async fn get(State(app_state): State<AppState>, _: ApiAdmin) -> Result<Json<Settings>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+
+    Ok(Json(Settings {
+        two_factor_policy: db.get_two_factor_policy().await?,
+    }))
+}
+
+// Change the settings
+pub async fn update(
+    State(app_state): State<AppState>,
+    admin: ApiAdmin,
+    body: Bytes,
+) -> Result<Json<Settings>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+    let update = parse_json::<SettingsUpdate>(&body)?;
+
+    if let Some(policy) = update.two_factor_policy {
+        db.set_two_factor_policy(policy).await?;
+    }
+
+    get(State(app_state), admin).await
+}
@@ -0,0 +1,71 @@
+use super::*;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+}
+
+// A newly created api token, the only time the token itself is shown
+#[derive(Serialize)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    api_token: ApiToken,
+    token: String,
+}
+
+// List all api tokens
+pub async fn list(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+) -> Result<Json<Vec<ApiToken>>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+
+    Ok(Json(db.get_api_tokens().await?))
+}
+
+// Create an api token
+pub async fn create(
+    State(app_state): State<AppState>,
+    admin: ApiAdmin,
+    body: Bytes,
+) -> Result<(StatusCode, Json<CreatedToken>), Error> {
+    let (_, _, _, _, db) = app_state.extract();
+    let new_token = parse_json::<NewToken>(&body)?;
+
+    // Validate the name
+    let name = new_token.name.trim();
+    if name.is_empty() {
+        return Err(Error::BadRequest("The name can't be empty".to_string()));
+    }
+
+    // Create the token
+    let (api_token, token) = db.create_api_token(name, &admin.name).await?;
+
+    Ok((StatusCode::CREATED, Json(CreatedToken { api_token, token })))
+}
+
+// Delete an api token
+pub async fn delete(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(id): Path<String>,
+) -> Result<StatusCode, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+    let id = parse_id(&id)?;
+
+    match db.delete_api_token(id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(Error::NotFound(format!(
+            "There is no api token with id {}",
+            id
+        ))),
+    }
+}
@@ -0,0 +1,172 @@
+use super::*;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UserUpdate {
+    admin: Option<bool>,
+    groups: Option<Vec<String>>,
+    password: Option<String>,
+    #[serde(default)]
+    reset_two_factor: bool,
+}
+
+// List all users
+pub async fn list(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+) -> Result<Json<Vec<User>>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+
+    Ok(Json(db.get_users().await?))
+}
+
+// Create a user
+pub async fn create(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    body: Bytes,
+) -> Result<(StatusCode, Json<User>), Error> {
+    let (_, _, _, _, db) = app_state.extract();
+    let new_user = parse_json::<NewUser>(&body)?;
+
+    // Validate the user
+    let username = new_user.username.trim();
+    if username.is_empty() {
+        return Err(Error::BadRequest("The username can't be empty".to_string()));
+    }
+    if new_user.password.is_empty() {
+        return Err(Error::BadRequest("The password can't be empty".to_string()));
+    }
+    if db.username_exists(username).await? {
+        return Err(Error::Conflict(format!(
+            "The username {} is already taken",
+            username
+        )));
+    }
+
+    let groups = parse_groups(&new_user.groups)?;
+
+    // Create the user
+    let id = db
+        .create_user(username, &new_user.password, new_user.admin)
+        .await?;
+    if !groups.is_empty() {
+        db.set_groups(id, &groups).await?;
+    }
+    let user = get_user(&db, id).await?;
+
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+// Get a user
+pub async fn get(
+    State(app_state): State<AppState>,
+    _: ApiAdmin,
+    Path(id): Path<String>,
+) -> Result<Json<User>, Error> {
+    let (_, _, _, _, db) = app_state.extract();
+
+    Ok(Json(get_user(&db, parse_id(&id)?).await?))
+}
+
+// Change the role and/or password of a user
+pub async fn update(
+    State(app_state): State<AppState>,
+    admin: ApiAdmin,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<User>, Error> {
+    let (mut sessions, _, _, _, db) = app_state.extract();
+    let user = get_user(&db, parse_id(&id)?).await?;
+    let update = parse_json::<UserUpdate>(&body)?;
+    let groups = update.groups.as_deref().map(parse_groups).transpose()?;
+
+    // Only what actually changes is written, and only that logs the user out
+    let new_admin = update.admin.filter(|new_admin| *new_admin != user.admin);
+    let groups = groups.filter(|groups| *groups != user.groups);
+
+    // Check everything before anything is saved
+    if new_admin == Some(false) {
+        if admin.is_user(&user.username) {
+            return Err(Error::Conflict(
+                "You can't remove your own admin rights".to_string(),
+            ));
+        }
+        if db.count_admins().await? <= 1 {
+            return Err(Error::Conflict(
+                "There has to be at least one admin".to_string(),
+            ));
+        }
+    }
+    if update.password.as_deref() == Some("") {
+        return Err(Error::BadRequest("The password can't be empty".to_string()));
+    }
+
+    // Save the role, groups, password and two-factor reset together
+    let changes = UserChanges {
+        admin: new_admin,
+        groups: groups.as_deref(),
+        password: update.password.as_deref(),
+        reset_two_factor: update.reset_two_factor && user.two_factor,
+    };
+    db.update_user(user.id, &changes).await?;
+
+    // Log the user out everywhere so changes to their password or rights take effect
+    let changed = new_admin.is_some() || groups.is_some() || update.password.is_some();
+    if changed && !admin.is_user(&user.username) {
+        sessions.delete_user(&user.username).await?;
+    }
+
+    Ok(Json(get_user(&db, user.id).await?))
+}
+
+// Delete a user
+pub async fn delete(
+    State(app_state): State<AppState>,
+    admin: ApiAdmin,
+    Path(id): Path<String>,
+) -> Result<StatusCode, Error> {
+    let (mut sessions, _, _, _, db) = app_state.extract();
+    let user = get_user(&db, parse_id(&id)?).await?;
+
+    // Don't lock everyone out
+    if admin.is_user(&user.username) {
+        return Err(Error::Conflict(
+            "You can't delete your own account".to_string(),
+        ));
+    }
+    if user.admin && db.count_admins().await? <= 1 {
+        return Err(Error::Conflict(
+            "There has to be at least one admin".to_string(),
+        ));
+    }
+
+    // Delete the user and log them out everywhere
+    db.delete_user(user.id).await?;
+    sessions.delete_user(&user.username).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_user(db: &Db, id: i64) -> Result<User, Error> {
+    db.get_user(id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("There is no user with id {}", id)))
+}
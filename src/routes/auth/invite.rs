@@ -0,0 +1,165 @@
+use super::login::get_query_param;
+use crate::*;
+
+use axum::{extract::State, response::Redirect};
+use axum_extra::extract::CookieJar;
+use hyper::{header::SET_COOKIE, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use urlencoding::{decode, encode};
+
+// Send the page to accept an invite to the user
+pub async fn invite_page(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Response<Body> {
+    // Initialize variables
+    let (_, _, conf, tera, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Get the invite
+    let token = get_query_param(&req, "token").unwrap_or_default();
+    let invite = match db.get_invite(&token).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => {
+            return error_page(
+                &tera,
+                StatusCode::NOT_FOUND,
+                "This invite link is not valid or has expired. Ask your admin for a new one.",
+            )
+        }
+        Err(err) => {
+            eprintln!("Failed to look up invite: {}", err);
+            return error_page(
+                &tera,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Oops! Something went wrong. Please give it another try.",
+            );
+        }
+    };
+
+    // Create the context
+    let mut context = tera::Context::new();
+    context.insert("title", &format!("Welcome, {}!", invite.username));
+    context.insert("invite_route", &(special_route.to_owned() + "/invite"));
+    context.insert("token", &invite.token);
+    context.insert("username", &invite.username);
+
+    // Add the csrf token for the form
+    let (csrf_token, csrf_cookie) = csrf_token(&conf, &jar);
+    context.insert("csrf_token", &csrf_token);
+
+    // Get the msg and color from the query
+    if let Some(msg) = get_query_param(&req, "msg") {
+        if let Ok(msg) = decode(&msg) {
+            context.insert("msg", &msg);
+        }
+    }
+    if let Some(status) = get_query_param(&req, "status") {
+        context.insert("status", &status);
+    }
+
+    // Render the invite page
+    let invite_page = check_err!(tera.render("invite.tera.html", &context));
+
+    // Send the invite page
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = csrf_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    res.body(Body::from(invite_page)).unwrap()
+}
+
+#[derive(Deserialize)]
+struct InviteData {
+    token: String,
+    password: String,
+    password_confirm: String,
+}
+
+// Create the user of an invite
+pub async fn invite_req(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Redirect {
+    // Initialize variables
+    let (_, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+    let login_error = |msg: &str| {
+        Redirect::to(&format!(
+            "{}/login?msg={}&status=error",
+            &special_route,
+            encode(msg)
+        ))
+    };
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return login_error("Oops! Something went wrong. Please give it another try."),
+    };
+    let invite_data =
+        match serde_urlencoded::from_bytes::<InviteData>(&body) {
+            Ok(data) => data,
+            Err(_) => return login_error(
+                "Oops! We couldn't process the information you provided. Can you please try again?",
+            ),
+        };
+    let invite_error = |msg: &str| {
+        Redirect::to(&format!(
+            "{}/invite?token={}&msg={}&status=error",
+            &special_route,
+            encode(&invite_data.token),
+            encode(msg)
+        ))
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return invite_error("Your form has expired. Please reload the page and try again.");
+    }
+
+    // Get the invite
+    let invite = match db.get_invite(&invite_data.token).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => {
+            return login_error(
+                "This invite link is not valid or has expired. Ask your admin for a new one.",
+            )
+        }
+        Err(_) => return invite_error("Oops! Something went wrong. Please give it another try."),
+    };
+
+    // Validate the form
+    if invite_data.password.is_empty() {
+        return invite_error("Please choose a password.");
+    }
+    if invite_data.password != invite_data.password_confirm {
+        return invite_error("The passwords don't match.");
+    }
+    match db.username_exists(&invite.username).await {
+        Ok(false) => (),
+        Ok(true) => return login_error(
+            "The username of this invite has already been taken. Ask your admin for a new invite.",
+        ),
+        Err(_) => return invite_error("Oops! Something went wrong. Please give it another try."),
+    }
+
+    // Create the user and use up the invite
+    if let Err(err) = db
+        .create_user(&invite.username, &invite_data.password, invite.admin)
+        .await
+    {
+        eprintln!("Failed to create invited user: {}", err);
+        return invite_error("Oops! Something went wrong. Please give it another try.");
+    }
+    if let Err(err) = db.delete_invite(&invite.token).await {
+        eprintln!("Failed to delete used invite: {}", err);
+    }
+
+    // Redirect the user to the login page
+    Redirect::to(&format!(
+        "{}/login?msg={}&status=success",
+        &special_route,
+        encode("Your account has been created. You can now log in.")
+    ))
+}
@@ -1,28 +1,44 @@
-use crate::*;
+use crate::{
+    routes::proxy::{request_host, select_proxy},
+    *,
+};
 
-use axum::{extract::State, response::Redirect};
-use axum_extra::extract::cookie::{Cookie, CookieJar};
-use hyper::{Body, Request, Response, StatusCode};
+use axum::{
+    extract::{ConnectInfo, State},
+    response::Redirect,
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::{header::SET_COOKIE, Body, Request, Response, StatusCode, Uri};
 use serde::Deserialize;
-use sha2::Digest;
+use std::net::SocketAddr;
 use urlencoding::{decode, encode};
 
 // Send the login page to the user
-// TODO: If there are no users in the database send page to make first admin user
 pub async fn login_page(
     State(app_state): State<AppState>,
     jar: CookieJar,
     req: Request<Body>,
-) -> Response<Body> {
+) -> Result<Response<Body>, Redirect> {
     // Initialize variables
     let (sessions, _, conf, tera, _) = app_state.extract();
     let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Send the user to the setup page when there are no users yet
+    if app_state.setup().pending().await {
+        return Err(Redirect::to(&format!("{}/setup", special_route)));
+    }
+
     let mut context = tera::Context::new();
 
     // Generate the routes
     context.insert("login_route", &(special_route.to_owned() + "/login"));
     context.insert("logout_route", &(special_route.to_owned() + "/logout"));
     context.insert("admin_route", &(special_route.to_owned() + "/admin"));
+    context.insert("account_route", &(special_route.to_owned() + "/account"));
+
+    // Add the csrf token for the forms
+    let (csrf_token, csrf_cookie) = csrf_token(&conf, &jar);
+    context.insert("csrf_token", &csrf_token);
 
     // Get cookie
     let cookie_name = check_err!(conf.get(CookieName));
@@ -66,30 +82,43 @@ pub async fn login_page(
         context.insert("status", &status);
     }
 
+    // Keep the page to return to after logging in
+    if let Some(next) = get_query_param(&req, "next") {
+        if let Ok(next) = decode(&next) {
+            context.insert("next", &next);
+        }
+    }
+
     // Render the login page
     let login_page = check_err!(tera.render("login.tera.html", &context));
 
     // Send the login page
-    Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::from(login_page))
-        .unwrap()
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = csrf_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    Ok(res.body(Body::from(login_page)).unwrap())
 }
 
 #[derive(Deserialize)]
 struct LoginData {
     username: String,
     password: String,
+    #[serde(default)]
+    next: String,
 }
 
 pub async fn login_req(
     State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     jar: CookieJar,
     req: Request<Body>,
 ) -> Result<(CookieJar, Redirect), Redirect> {
     // Initialize variables
     let (mut sessions, _, conf, _, db) = app_state.extract();
     let special_route = check_err!(conf.get(SpecialRoute));
+    let host = request_host(&req);
+    let ip = client_ip(&conf, req.headers(), addr.ip());
 
     // Get data from the request using serde
     let body = match hyper::body::to_bytes(req.into_body()).await {
@@ -113,34 +142,61 @@ pub async fn login_req(
         }
     };
 
+    // Only return to pages that are proxied on this host, anything else could send the user to another site
+    let next = match valid_next(&login_data.next, host.as_deref(), &db, &special_route).await {
+        true => Some(login_data.next),
+        false => None,
+    };
+    let login_error = |msg: &str, status: &str| {
+        let next = match &next {
+            Some(next) => format!("&next={}", encode(next)),
+            None => String::new(),
+        };
+        Redirect::to(&format!(
+            "{}/login?msg={}&status={}{}",
+            &special_route,
+            encode(msg),
+            status,
+            next
+        ))
+    };
+
+    // Check the csrf token
+    if !csrf_valid(&conf, &jar, &body) {
+        return Err(login_error(
+            "Your form has expired. Please reload the page and try again.",
+            "warning",
+        ));
+    }
+
     // Get the username and password
     let (username, password) = (login_data.username, login_data.password);
 
-    // Hash the password
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(password);
-    let password = hex::encode(hasher.finalize());
+    // Refuse to check the password while the username or ip address is locked out
+    let lockouts = app_state.lockouts();
+    if let Some(wait) = lockouts.locked(&username, ip).await {
+        return Err(login_error(&wait_message(wait), "error"));
+    }
 
     // Validate the user
     let db_result = db.validate_user(&username, &password).await;
     let valid_user = match db_result {
         Ok(valid_user) => valid_user,
         Err(_) => {
-            return Err(Redirect::to(&format!(
-                "{}/login?msg={}&status=error",
-                &special_route,
-                encode("Oops! Something went wrong. Please give it another try.")
-            )));
+            return Err(login_error(
+                "Oops! Something went wrong. Please give it another try.",
+                "error",
+            ));
         }
     };
 
     // Give response if the user is not valid
     if !valid_user {
-        return Err(Redirect::to(&format!(
-            "{}/login?msg={}&status=warning",
-            &special_route,
-            encode("Sorry, either your username or password is incorrect. Please double-check and try again.")
-        )));
+        lockouts.failed(&username, ip, &conf).await;
+        return Err(login_error(
+            "Sorry, either your username or password is incorrect. Please double-check and try again.",
+            "warning",
+        ));
     }
 
     // Get cookie name
@@ -156,17 +212,16 @@ pub async fn login_req(
     // Get the username from the session
     let username_from_session = match session {
         Some(mut session) => {
-            // Get variables from the session
-            let username = session.user.clone();
-
             // Check if the session is expired
             if session.expired() {
                 None
             } else {
                 // Renew the session
-                session.renew();
+                if let Err(err) = sessions.renew(&mut session, &conf).await {
+                    eprintln!("Failed to renew session: {}", err);
+                }
 
-                Some(username)
+                Some(session.user)
             }
         }
         None => None,
@@ -181,25 +236,106 @@ pub async fn login_req(
         )));
     }
 
+    // Ask for the second factor first when the user has it, or has to set it up
+    let two_factor = async {
+        let secret = db.get_totp_secret(&username).await?;
+        let required = db
+            .get_two_factor_policy()
+            .await?
+            .required(db.is_admin(&username).await?);
+        Ok::<_, Error>((secret, required))
+    };
+    match two_factor.await {
+        Ok((None, false)) => (),
+        Ok((secret, _)) => {
+            let enroll_secret = match secret {
+                Some(_) => None,
+                None => Some(totp::generate_secret()),
+            };
+            let token = app_state
+                .pending_logins()
+                .start(username, next, enroll_secret)
+                .await;
+
+            return Ok((
+                jar.add(pending_login_cookie(&conf, token)),
+                Redirect::to(&format!("{}/2fa", &special_route)),
+            ));
+        }
+        Err(err) => {
+            eprintln!("Failed to look up two-factor authentication: {}", err);
+            return Err(login_error(
+                "Oops! Something went wrong. Please give it another try.",
+                "error",
+            ));
+        }
+    }
+    lockouts.succeeded(&username).await;
+
     // Create a new session
-    let session = sessions.new_session(username, &conf, &db).await;
+    let (session, token) = match sessions.new_session(username, &conf).await {
+        Ok(new_session) => new_session,
+        Err(err) => {
+            eprintln!("Failed to create session: {}", err);
+            return Err(login_error(
+                "Oops! Something went wrong. Please give it another try.",
+                "error",
+            ));
+        }
+    };
 
     // Create a new cookie
-    let mut cookie = Cookie::new(cookie_name, session.token);
-    cookie.set_path("/");
+    let cookie = session_cookie(&conf, token, &session);
 
-    // Redirect the user to the home page
+    // Redirect the user to the page they came from, or the login page
     Ok((
         jar.add(cookie),
-        Redirect::to(&format!(
+        logged_in_redirect(&special_route, next.as_deref()),
+    ))
+}
+
+// Send a user that just logged in to the page they came from, or the login page
+pub fn logged_in_redirect(special_route: &str, next: Option<&str>) -> Redirect {
+    Redirect::to(&logged_in_url(special_route, next))
+}
+
+// The page to send a user that just logged in to
+pub fn logged_in_url(special_route: &str, next: Option<&str>) -> String {
+    match next {
+        Some(next) => next.to_owned(),
+        None => format!(
             "{}/login?msg={}&status=success",
-            &special_route,
+            special_route,
             encode("You have successfully logged in.")
-        )),
-    ))
+        ),
+    }
 }
 
-fn get_query_param(req: &Request<Body>, param: &str) -> Option<String> {
+// Check that a page to return to after logging in is a relative url of a page on this host that proxrs protects
+pub async fn valid_next(next: &str, host: Option<&str>, db: &Db, special_route: &str) -> bool {
+    // Only allow relative urls, "//" and "/\" would be read as another host by browsers
+    if !next.starts_with('/') || next.starts_with("//") || next.contains('\\') {
+        return false;
+    }
+    let uri = match next.parse::<Uri>() {
+        Ok(uri) if uri.host().is_none() => uri,
+        _ => return false,
+    };
+
+    // Check that a route is configured for the page
+    let host = match host {
+        Some(host) => host,
+        None => return false,
+    };
+    match db.get_proxies_for_host(host).await {
+        // Hosts without routes are served by an edge proxy asking the verify endpoint, any page there is fine
+        Ok(proxies) if proxies.is_empty() => true,
+        Ok(proxies) => select_proxy(proxies, uri.path(), special_route).is_some(),
+        Err(_) => false,
+    }
+}
+
+pub fn get_query_param(req: &Request<Body>, param: &str) -> Option<String> {
     // Get the query
     let query = req.uri().query().unwrap_or("");
 
@@ -219,3 +355,69 @@ fn get_query_param(req: &Request<Body>, param: &str) -> Option<String> {
         Some(param)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn db() -> Db {
+        let db = Db::new(":memory:".to_string()).await.unwrap();
+        let proxy = Proxy {
+            name: "app.local".to_string(),
+            targets: vec![Target {
+                host: "127.0.0.1".to_string(),
+                port: 3000,
+            }],
+            path_prefix: "/app".to_string(),
+            is_enabled: true,
+            ..Proxy::default()
+        };
+        db.create_proxy(&proxy).await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn only_accepts_pages_of_protected_routes() {
+        let db = db().await;
+        let cases = [
+            ("/app", true),
+            ("/app/page?tab=2#top", true),
+            ("/app/%2F%2Fevil.com", true),
+            ("/app/%5Cevil.com", true),
+            ("/other", false),
+            ("/proxrs/account", false),
+            ("", false),
+            ("app/page", false),
+            ("//evil.com", false),
+            ("//evil.com/app", false),
+            ("///evil.com", false),
+            ("/\\evil.com", false),
+            ("\\\\evil.com", false),
+            ("/app\\..\\..\\evil.com", false),
+            ("https://evil.com", false),
+            ("https://evil.com/app", false),
+            ("https:evil.com", false),
+            ("javascript:alert(1)", false),
+            ("%2F%2Fevil.com", false),
+            ("%2F%5Cevil.com", false),
+            ("https%3A%2F%2Fevil.com", false),
+            ("/\t/evil.com", false),
+            ("/\n/evil.com", false),
+        ];
+        for (next, expected) in cases {
+            let valid = valid_next(next, Some("app.local"), &db, "/proxrs").await;
+            assert_eq!(valid, expected, "next {:?}", next);
+        }
+
+        // Without the host there is no telling which site the page is on
+        assert!(!valid_next("/app", None, &db, "/proxrs").await);
+        // Hosts without routes are checked by an edge proxy
+        assert!(valid_next("/anything", Some("edge.local"), &db, "/proxrs").await);
+    }
+
+    #[test]
+    fn sends_users_without_a_page_to_the_login_page() {
+        assert_eq!(logged_in_url("/proxrs", Some("/app/page")), "/app/page");
+        assert!(logged_in_url("/proxrs", None).starts_with("/proxrs/login?msg="));
+    }
+}
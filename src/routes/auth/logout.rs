@@ -1,7 +1,7 @@
 use crate::*;
 
 use axum::{extract::State, response::Redirect};
-use axum_extra::extract::cookie::{Cookie, CookieJar};
+use axum_extra::extract::cookie::CookieJar;
 use hyper::{Body, Request};
 use urlencoding::encode;
 
@@ -9,13 +9,25 @@ use urlencoding::encode;
 pub async fn logout(
     State(app_state): State<AppState>,
     jar: CookieJar,
-    _req: Request<Body>,
+    req: Request<Body>,
 ) -> Result<(CookieJar, Redirect), Redirect> {
     // Initialize variables
     let (mut sessions, _, conf, _, _) = app_state.extract();
     let special_route = check_err!(conf.get(SpecialRoute));
     let cookie_name = check_err!(conf.get(CookieName));
 
+    // Check the csrf token, so other sites can't log the user out
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    if !csrf_valid(&conf, &jar, &body) {
+        return Err(Redirect::to(&format!(
+            "{}/login?msg={}&status=warning",
+            special_route,
+            encode("Your form has expired. Please reload the page and try again.")
+        )));
+    }
+
     // Get cookie
     let cookie = match jar.get(&cookie_name) {
         Some(cookie) => cookie,
@@ -55,7 +67,7 @@ pub async fn logout(
     // Delete the session
     match sessions.delete(session).await {
         Ok(_) => (),
-        Err(()) => {
+        Err(_) => {
             // Redirect to the login page
             return Err(Redirect::to(&format!(
                 "{}/login?msg={}&status=warning",
@@ -66,8 +78,7 @@ pub async fn logout(
     }
 
     // Unset the cookie
-    let mut cookie = Cookie::new(cookie_name, "");
-    cookie.set_path("/");
+    let cookie = removal_cookie(&conf);
 
     // Redirect to the login page
     Ok((
@@ -1,2 +1,6 @@
+pub mod invite;
 pub mod login;
 pub mod logout;
+pub mod passkey;
+pub mod setup;
+pub mod two_factor;
@@ -0,0 +1,276 @@
+use super::login::{logged_in_url, valid_next};
+use crate::{
+    routes::{api::parse_json, proxy::request_host},
+    *,
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    Json,
+};
+use axum_extra::extract::cookie::CookieJar;
+use hyper::{Body, Request};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use urlencoding::encode;
+
+// Longest name a passkey can be given
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Deserialize)]
+struct OptionsRequest {
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    csrf_token: String,
+    #[serde(default)]
+    next: String,
+    id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    csrf_token: String,
+    #[serde(default)]
+    name: String,
+    client_data_json: String,
+    attestation_object: String,
+}
+
+// Give the login page a challenge to sign with a passkey
+pub async fn passkey_login_options(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Json<Value>, Error> {
+    // Initialize variables
+    let (_, _, conf, _, _) = app_state.extract();
+    let rp = relying_party(&conf, request_host(&req))?;
+    let data = read_json::<OptionsRequest>(req).await?;
+    check_csrf(&conf, &jar, &data.csrf_token)?;
+
+    // Any passkey of any user can answer it, the user picks one in the browser
+    let challenge = app_state
+        .passkey_challenges()
+        .issue(ChallengePurpose::Login)
+        .await;
+
+    Ok(Json(
+        json!({ "publicKey": webauthn::request_options(&rp, &challenge) }),
+    ))
+}
+
+// Log in with a signed challenge, passkeys verify the user themselves so no second factor is asked
+pub async fn passkey_login(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<(CookieJar, Json<Value>), Error> {
+    // Initialize variables
+    let (mut sessions, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+    let host = request_host(&req);
+    let rp = relying_party(&conf, host.clone())?;
+    let ip = client_ip(&conf, req.headers(), addr.ip());
+    let data = read_json::<LoginRequest>(req).await?;
+    check_csrf(&conf, &jar, &data.csrf_token)?;
+
+    // Decode the response of the authenticator
+    let client_data_json = webauthn::decode(&data.client_data_json).map_err(bad_request)?;
+    let authenticator_data = webauthn::decode(&data.authenticator_data).map_err(bad_request)?;
+    let signature = webauthn::decode(&data.signature).map_err(bad_request)?;
+
+    // The challenge has to be one that was given out for logging in
+    let challenge = webauthn::challenge_of(&client_data_json).map_err(bad_request)?;
+    let purpose = app_state.passkey_challenges().take(&challenge).await;
+    if purpose != Some(ChallengePurpose::Login) {
+        return Err(Error::Unauthorized(EXPIRED_MSG.to_string()));
+    }
+
+    // Find the passkey and the user it belongs to
+    let passkey = db
+        .find_passkey(&data.id)
+        .await?
+        .ok_or_else(|| Error::Unauthorized("This passkey is not registered".to_string()))?;
+
+    // Failed passkey logins count towards the lockout like wrong passwords
+    let lockouts = app_state.lockouts();
+    if let Some(wait) = lockouts.locked(&passkey.user, ip).await {
+        return Err(Error::Forbidden(wait_message(wait)));
+    }
+    let sign_count = match webauthn::verify_assertion(
+        &rp,
+        &challenge,
+        &passkey.public_key,
+        passkey.sign_count,
+        &client_data_json,
+        &authenticator_data,
+        &signature,
+    ) {
+        Ok(sign_count) => sign_count,
+        Err(msg) => {
+            lockouts.failed(&passkey.user, ip, &conf).await;
+            return Err(Error::Unauthorized(msg.to_string()));
+        }
+    };
+    db.use_passkey(passkey.id, sign_count).await?;
+    lockouts.succeeded(&passkey.user).await;
+
+    // Only return to pages that are proxied on this host
+    let next = match valid_next(&data.next, host.as_deref(), &db, &special_route).await {
+        true => Some(data.next),
+        false => None,
+    };
+
+    // Create a new session, dropping a password login that was still waiting for its second factor
+    let (session, token) = sessions.new_session(passkey.user, &conf).await?;
+    let jar = jar
+        .add(session_cookie(&conf, token, &session))
+        .add(pending_login_removal_cookie(&conf));
+
+    Ok((
+        jar,
+        Json(json!({ "redirect": logged_in_url(&special_route, next.as_deref()) })),
+    ))
+}
+
+// Give the account page the options to create a passkey with
+pub async fn passkey_register_options(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Json<Value>, Error> {
+    // Initialize variables
+    let (sessions, _, conf, _, db) = app_state.extract();
+    let session = logged_in(&sessions, &jar, &conf).await?;
+    let rp = relying_party(&conf, request_host(&req))?;
+    let data = read_json::<OptionsRequest>(req).await?;
+    check_csrf(&conf, &jar, &data.csrf_token)?;
+
+    // Don't let the authenticator make a second passkey for the same account
+    let exclude_credentials = db
+        .get_passkeys(&session.user)
+        .await?
+        .into_iter()
+        .map(|passkey| passkey.credential_id)
+        .collect::<Vec<_>>();
+
+    // The user handle has to stay the same for the user, but shouldn't be the username itself
+    let user_handle = Sha256::digest(session.user.as_bytes());
+    let challenge = app_state
+        .passkey_challenges()
+        .issue(ChallengePurpose::Register(session.user.clone()))
+        .await;
+
+    Ok(Json(json!({
+        "publicKey": webauthn::creation_options(
+            &rp,
+            &user_handle,
+            &session.user,
+            &challenge,
+            &exclude_credentials,
+        )
+    })))
+}
+
+// Save the passkey the browser created
+pub async fn passkey_register(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Json<Value>, Error> {
+    // Initialize variables
+    let (sessions, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+    let session = logged_in(&sessions, &jar, &conf).await?;
+    let rp = relying_party(&conf, request_host(&req))?;
+    let data = read_json::<RegisterRequest>(req).await?;
+    check_csrf(&conf, &jar, &data.csrf_token)?;
+
+    // Decode the response of the authenticator
+    let client_data_json = webauthn::decode(&data.client_data_json).map_err(bad_request)?;
+    let attestation_object = webauthn::decode(&data.attestation_object).map_err(bad_request)?;
+
+    // The challenge has to be one that was given out to this user for adding a passkey
+    let challenge = webauthn::challenge_of(&client_data_json).map_err(bad_request)?;
+    let purpose = app_state.passkey_challenges().take(&challenge).await;
+    if purpose != Some(ChallengePurpose::Register(session.user.clone())) {
+        return Err(Error::BadRequest(EXPIRED_MSG.to_string()));
+    }
+    let credential =
+        webauthn::verify_registration(&rp, &challenge, &client_data_json, &attestation_object)
+            .map_err(bad_request)?;
+
+    // Save the passkey under the name the user gave it
+    let name = match data.name.trim() {
+        "" => "Passkey".to_string(),
+        name => name.chars().take(MAX_NAME_LEN).collect(),
+    };
+    db.create_passkey(&session.user, &name, &credential).await?;
+
+    Ok(Json(json!({
+        "redirect": format!(
+            "{}/account?msg={}&status=success",
+            special_route,
+            encode("Your passkey has been added.")
+        )
+    })))
+}
+
+const EXPIRED_MSG: &str = "The passkey request has expired. Please try again.";
+
+// Passkeys are made for the cookie domain when it covers this host, so they work on every subdomain
+fn relying_party(conf: &Config, host: Option<String>) -> Result<webauthn::RelyingParty, Error> {
+    let host = host.ok_or_else(|| Error::BadRequest("The request has no host".to_string()))?;
+    let domain = check_err!(conf.get(CookieDomain))
+        .trim_start_matches('.')
+        .to_lowercase();
+
+    let id = if !domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain))) {
+        domain
+    } else {
+        host
+    };
+
+    Ok(webauthn::RelyingParty {
+        id,
+        name: "proxrs".to_string(),
+    })
+}
+
+// Get the session of the user managing their passkeys
+async fn logged_in(sessions: &Sessions, jar: &CookieJar, conf: &Config) -> Result<Session, Error> {
+    sessions
+        .get_from_cookie(jar, conf)
+        .await
+        .ok_or_else(|| Error::Unauthorized("Please log in first".to_string()))
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Error> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|err| Error::BadRequest(err.to_string()))?;
+
+    parse_json(&body)
+}
+
+fn check_csrf(conf: &Config, jar: &CookieJar, token: &str) -> Result<(), Error> {
+    match csrf_matches(conf, jar, token) {
+        true => Ok(()),
+        false => Err(Error::Forbidden(
+            "Your page has expired. Please reload the page and try again.".to_string(),
+        )),
+    }
+}
+
+fn bad_request(msg: &str) -> Error {
+    Error::BadRequest(msg.to_string())
+}
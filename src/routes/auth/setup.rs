@@ -0,0 +1,140 @@
+use super::login::get_query_param;
+use crate::*;
+
+use axum::{extract::State, response::Redirect};
+use axum_extra::extract::CookieJar;
+use hyper::{header::SET_COOKIE, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use urlencoding::{decode, encode};
+
+// Send the first-run setup page to the user
+pub async fn setup_page(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Response<Body>, Redirect> {
+    // Initialize variables
+    let (_, _, conf, tera, _) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // The setup can only be done once
+    if !app_state.setup().pending().await {
+        return Err(Redirect::to(&format!("{}/login", special_route)));
+    }
+
+    // Create the context
+    let mut context = tera::Context::new();
+    context.insert("title", "Set up proxrs");
+    context.insert("setup_route", &(special_route.to_owned() + "/setup"));
+
+    // Add the csrf token for the form
+    let (csrf_token, csrf_cookie) = csrf_token(&conf, &jar);
+    context.insert("csrf_token", &csrf_token);
+
+    // Get the msg and color from the query
+    if let Some(msg) = get_query_param(&req, "msg") {
+        if let Ok(msg) = decode(&msg) {
+            context.insert("msg", &msg);
+        }
+    }
+    if let Some(status) = get_query_param(&req, "status") {
+        context.insert("status", &status);
+    }
+
+    // Render the setup page
+    let setup_page = check_err!(tera.render("setup.tera.html", &context));
+
+    // Send the setup page
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = csrf_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    Ok(res.body(Body::from(setup_page)).unwrap())
+}
+
+#[derive(Deserialize)]
+struct SetupData {
+    token: String,
+    username: String,
+    password: String,
+    password_confirm: String,
+}
+
+// Create the first admin user
+pub async fn setup_req(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Redirect {
+    // Initialize variables
+    let (_, _, conf, _, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+    let setup_error = |msg: &str| {
+        Redirect::to(&format!(
+            "{}/setup?msg={}&status=error",
+            &special_route,
+            encode(msg)
+        ))
+    };
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return setup_error("Oops! Something went wrong. Please give it another try."),
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return setup_error("Your form has expired. Please reload the page and try again.");
+    }
+    let setup_data =
+        match serde_urlencoded::from_bytes::<SetupData>(&body) {
+            Ok(data) => data,
+            Err(_) => return setup_error(
+                "Oops! We couldn't process the information you provided. Can you please try again?",
+            ),
+        };
+
+    // Hold the setup token while creating the user so this can only happen once
+    let setup = app_state.setup();
+    let mut token = setup.token().await;
+    let expected = match token.as_ref() {
+        Some(token) => token,
+        None => {
+            return Redirect::to(&format!(
+                "{}/login?msg={}&status=warning",
+                &special_route,
+                encode("Proxrs has already been set up.")
+            ))
+        }
+    };
+
+    // Validate the form
+    if !password::constant_time_eq(setup_data.token.trim().as_bytes(), expected.as_bytes()) {
+        return setup_error("The setup token is not correct. Check the console output of proxrs.");
+    }
+    let username = setup_data.username.trim();
+    if username.is_empty() {
+        return setup_error("Please choose a username.");
+    }
+    if setup_data.password.is_empty() {
+        return setup_error("Please choose a password.");
+    }
+    if setup_data.password != setup_data.password_confirm {
+        return setup_error("The passwords don't match.");
+    }
+
+    // Create the first admin
+    if let Err(err) = db.create_user(username, &setup_data.password, true).await {
+        eprintln!("Failed to create the first admin: {}", err);
+        return setup_error("Oops! Something went wrong. Please give it another try.");
+    }
+
+    // The setup is done, the token can't be used again
+    *token = None;
+
+    // Redirect the user to the login page
+    Redirect::to(&format!(
+        "{}/login?msg={}&status=success",
+        &special_route,
+        encode("Your admin account has been created. You can now log in.")
+    ))
+}
@@ -0,0 +1,255 @@
+use super::login::{get_query_param, logged_in_redirect};
+use crate::*;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    response::{IntoResponse, Redirect},
+};
+use axum_extra::extract::cookie::CookieJar;
+use chrono::Utc;
+use hyper::{header::SET_COOKIE, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use urlencoding::{decode, encode};
+
+// Send the second login step to the user
+pub async fn two_factor_page(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Result<Response<Body>, Redirect> {
+    // Initialize variables
+    let (_, _, conf, tera, _) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Get the login waiting for its second factor
+    let pending = match pending_login(&app_state, &jar, &conf).await {
+        Some((_, pending)) => pending,
+        None => return Err(expired_redirect(&special_route)),
+    };
+
+    // Create the context
+    let mut context = tera::Context::new();
+    context.insert("title", "Two-factor authentication");
+    context.insert("two_factor_route", &(special_route.to_owned() + "/2fa"));
+
+    // Add the csrf token for the form
+    let (csrf_token, csrf_cookie) = csrf_token(&conf, &jar);
+    context.insert("csrf_token", &csrf_token);
+
+    // Show the qr code when the user has to set up two-factor authentication first
+    if let Some(secret) = &pending.enroll_secret {
+        let uri = totp::otpauth_uri("proxrs", &pending.user, secret);
+        context.insert("qr_code", &totp::qr_svg(&uri));
+        context.insert("secret", secret);
+    }
+
+    // Get the msg and color from the query
+    if let Some(msg) = get_query_param(&req, "msg") {
+        if let Ok(msg) = decode(&msg) {
+            context.insert("msg", &msg);
+        }
+    }
+    if let Some(status) = get_query_param(&req, "status") {
+        context.insert("status", &status);
+    }
+
+    // Render the two-factor page
+    let two_factor_page = check_err!(tera.render("two_factor.tera.html", &context));
+
+    // Send the two-factor page
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = csrf_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    Ok(res.body(Body::from(two_factor_page)).unwrap())
+}
+
+#[derive(Deserialize)]
+struct TwoFactorData {
+    code: String,
+}
+
+// Check the second factor and finish the login
+pub async fn two_factor_req(
+    State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> axum::response::Response {
+    // Initialize variables
+    let (mut sessions, _, conf, tera, db) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+    let two_factor_error = |msg: &str, status: &str| {
+        Redirect::to(&format!(
+            "{}/2fa?msg={}&status={}",
+            &special_route,
+            encode(msg),
+            status
+        ))
+        .into_response()
+    };
+    let ip = client_ip(&conf, req.headers(), addr.ip());
+
+    // Get data from the request using serde
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => {
+            return two_factor_error(
+                "Oops! Something went wrong. Please give it another try.",
+                "error",
+            )
+        }
+    };
+    if !csrf_valid(&conf, &jar, &body) {
+        return two_factor_error(
+            "Your form has expired. Please reload the page and try again.",
+            "warning",
+        );
+    }
+    let two_factor_data =
+        match serde_urlencoded::from_bytes::<TwoFactorData>(&body) {
+            Ok(data) => data,
+            Err(_) => return two_factor_error(
+                "Oops! We couldn't process the information you provided. Can you please try again?",
+                "error",
+            ),
+        };
+
+    // Get the login waiting for its second factor
+    let (token, pending) = match pending_login(&app_state, &jar, &conf).await {
+        Some(pending) => pending,
+        None => return expired_redirect(&special_route).into_response(),
+    };
+
+    // Wrong codes count towards the lockout of the user as well
+    let lockouts = app_state.lockouts();
+    if let Some(wait) = lockouts.locked(&pending.user, ip).await {
+        return two_factor_error(&wait_message(wait), "error");
+    }
+
+    // Check the code, a new secret is only saved once the user has shown it works
+    let code = &two_factor_data.code;
+    let valid = match &pending.enroll_secret {
+        Some(secret) => match totp::verify(secret, code, Utc::now().timestamp()) {
+            Some(step) => {
+                async {
+                    db.set_totp_secret(&pending.user, Some(secret)).await?;
+                    db.use_totp_step(&pending.user, step).await
+                }
+                .await
+            }
+            None => Ok(false),
+        },
+        None => db.validate_two_factor(&pending.user, code).await,
+    };
+    let pending_logins = app_state.pending_logins();
+    match valid {
+        Ok(true) => (),
+        Ok(false) => {
+            lockouts.failed(&pending.user, ip, &conf).await;
+            if pending_logins.failed(&token).await {
+                return two_factor_error("That code is not correct. Please try again.", "warning");
+            }
+
+            // Too many wrong codes, start over with the password
+            return (
+                jar.add(pending_login_removal_cookie(&conf)),
+                Redirect::to(&format!(
+                    "{}/login?msg={}&status=warning",
+                    &special_route,
+                    encode("Too many wrong codes. Please log in again.")
+                )),
+            )
+                .into_response();
+        }
+        Err(err) => {
+            eprintln!("Failed to check two-factor code: {}", err);
+            return two_factor_error(
+                "Oops! Something went wrong. Please give it another try.",
+                "error",
+            );
+        }
+    }
+    pending_logins.finish(&token).await;
+    lockouts.succeeded(&pending.user).await;
+
+    // Give the user recovery codes when two-factor authentication was just set up
+    let recovery_codes = match pending.enroll_secret {
+        Some(_) => match db.create_recovery_codes(&pending.user).await {
+            Ok(codes) => Some(codes),
+            Err(err) => {
+                eprintln!("Failed to create recovery codes: {}", err);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Create a new session
+    let (session, session_token) = match sessions.new_session(pending.user, &conf).await {
+        Ok(new_session) => new_session,
+        Err(err) => {
+            eprintln!("Failed to create session: {}", err);
+            return (
+                jar.add(pending_login_removal_cookie(&conf)),
+                Redirect::to(&format!(
+                    "{}/login?msg={}&status=error",
+                    &special_route,
+                    encode("Oops! Something went wrong. Please give it another try.")
+                )),
+            )
+                .into_response();
+        }
+    };
+    let jar = jar
+        .add(session_cookie(&conf, session_token, &session))
+        .add(pending_login_removal_cookie(&conf));
+
+    // Show the recovery codes once, or send the user on
+    match recovery_codes {
+        Some(recovery_codes) => {
+            let mut context = tera::Context::new();
+            context.insert("title", "Two-factor authentication");
+            context.insert("recovery_codes", &recovery_codes);
+            context.insert(
+                "continue_route",
+                &pending
+                    .next
+                    .unwrap_or_else(|| special_route.to_owned() + "/login"),
+            );
+            let two_factor_page = check_err!(tera.render("two_factor.tera.html", &context));
+
+            (jar, Response::new(Body::from(two_factor_page))).into_response()
+        }
+        None => (
+            jar,
+            logged_in_redirect(&special_route, pending.next.as_deref()),
+        )
+            .into_response(),
+    }
+}
+
+// Get the login waiting for its second factor from the cookie
+async fn pending_login(
+    app_state: &AppState,
+    jar: &CookieJar,
+    conf: &Config,
+) -> Option<(String, PendingLogin)> {
+    let token = jar
+        .get(&pending_login_cookie_name(conf))?
+        .value()
+        .to_owned();
+    let pending = app_state.pending_logins().get(&token).await?;
+
+    Some((token, pending))
+}
+
+// Send the user back to the login page when the second step took too long
+fn expired_redirect(special_route: &str) -> Redirect {
+    Redirect::to(&format!(
+        "{}/login?msg={}&status=warning",
+        special_route,
+        encode("Your login has expired. Please log in again.")
+    ))
+}
@@ -0,0 +1,500 @@
+use crate::*;
+
+use flate2::write::{GzDecoder, GzEncoder};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG},
+    Body, HeaderMap, Response, StatusCode,
+};
+use regex::bytes::Regex;
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+// Content types that can hold urls of the site
+const REWRITE_TYPES: [&str; 6] = [
+    "text/html",
+    "application/xhtml+xml",
+    "text/css",
+    "text/javascript",
+    "application/javascript",
+    "application/x-javascript",
+];
+
+// Encodings the rewriter can read and write
+const ENCODINGS: [&str; 3] = ["gzip", "br", "identity"];
+
+// Root relative urls in html attributes and css, e.g. href="/app.css" and url(/logo.png)
+const HTML_URLS: &str = r#"(?i:\b(?:href|src|action|formaction|poster)\s{0,8}=\s{0,8}["']?/)"#;
+const CSS_URLS: &str = r#"(?i:url\(\s{0,8}["']?/)"#;
+
+// Longest text the html and css expressions match
+const MAX_URL_MATCH: usize = 32;
+
+// Only ask the upstream for encodings the rewriter can handle
+pub(crate) fn limit_accept_encoding(headers: &mut HeaderMap) {
+    let accepted = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| {
+            let name = coding.split(';').next().unwrap_or_default().trim();
+            ENCODINGS
+                .iter()
+                .any(|known| name.eq_ignore_ascii_case(known))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    headers.remove(ACCEPT_ENCODING);
+    if let Ok(value) = HeaderValue::from_str(&accepted) {
+        if !accepted.is_empty() {
+            headers.insert(ACCEPT_ENCODING, value);
+        }
+    }
+}
+
+// Add the route prefix to the urls in html, css and javascript bodies while they stream to the client
+pub(crate) fn rewrite_response_body(proxy: &Proxy, res: Response<Body>) -> Response<Body> {
+    if proxy.prefix().is_empty() || !has_body(res.status()) || !is_rewritable(res.headers()) {
+        return res;
+    }
+
+    // Bodies in an encoding the rewriter doesn't know are passed on as they are
+    let encoding = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("identity")
+        .trim()
+        .to_lowercase();
+    let mut rewrite = match BodyRewrite::new(&encoding, proxy) {
+        Some(rewrite) => rewrite,
+        None => return res,
+    };
+
+    // The length and the checksum change with the rewrite, and the bytes no longer match the etag
+    let (mut parts, mut upstream) = res.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove("content-md5");
+    weaken_etag(&mut parts.headers);
+
+    let (mut sender, body) = Body::channel();
+    let site = proxy.name.clone();
+    tokio::spawn(async move {
+        while let Some(chunk) = upstream.data().await {
+            let rewritten = chunk
+                .map_err(io::Error::other)
+                .and_then(|chunk| rewrite.push(&chunk));
+            match rewritten {
+                Ok(data) if data.is_empty() => (),
+                Ok(data) => {
+                    // The client went away
+                    if sender.send_data(data.into()).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Failed to rewrite response of {}: {}", site, err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+
+        match rewrite.finish() {
+            Ok(data) => {
+                let _ = sender.send_data(data.into()).await;
+            }
+            Err(err) => {
+                eprintln!("Failed to rewrite response of {}: {}", site, err);
+                sender.abort();
+            }
+        }
+    });
+
+    Response::from_parts(parts, body)
+}
+
+// Informational, 204 and 304 responses never have a body
+fn has_body(status: StatusCode) -> bool {
+    !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+}
+
+// A rewritten body is only equivalent to the upstream one, so a strong etag becomes a weak one
+fn weaken_etag(headers: &mut HeaderMap) {
+    let etag = match headers.get(ETAG).and_then(|value| value.to_str().ok()) {
+        Some(etag) if etag.starts_with("W/") => return,
+        Some(etag) => HeaderValue::from_str(&format!("W/{}", etag)).ok(),
+        None => return,
+    };
+    match etag {
+        Some(etag) => headers.insert(ETAG, etag),
+        None => headers.remove(ETAG),
+    };
+}
+
+fn is_rewritable(headers: &HeaderMap) -> bool {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    REWRITE_TYPES.contains(&content_type.as_str())
+}
+
+// Decodes the body, rewrites the urls in it and encodes it again
+struct BodyRewrite {
+    decoder: Decoder,
+    urls: UrlRewriter,
+    encoder: Encoder,
+}
+
+impl BodyRewrite {
+    fn new(encoding: &str, proxy: &Proxy) -> Option<Self> {
+        let (decoder, encoder) = match encoding {
+            "identity" => (Decoder::Identity, Encoder::Identity),
+            "gzip" | "x-gzip" => (
+                Decoder::Gzip(Box::new(GzDecoder::new(Vec::new()))),
+                Encoder::Gzip(Box::new(GzEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::default(),
+                ))),
+            ),
+            "br" => (
+                Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096))),
+                Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                    Vec::new(),
+                    4096,
+                    5,
+                    22,
+                ))),
+            ),
+            _ => return None,
+        };
+
+        Some(Self {
+            decoder,
+            urls: UrlRewriter::new(proxy.prefix(), proxy.patterns())?,
+            encoder,
+        })
+    }
+
+    fn push(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let decoded = self.decoder.write(data)?;
+        let rewritten = self.urls.push(&decoded);
+        self.encoder.write(&rewritten)
+    }
+
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        let decoded = self.decoder.finish()?;
+        let mut rewritten = self.urls.push(&decoded);
+        rewritten.extend(self.urls.finish());
+        let mut encoded = self.encoder.write(&rewritten)?;
+        encoded.extend(self.encoder.finish()?);
+
+        Ok(encoded)
+    }
+}
+
+// Puts the route prefix in front of the root relative urls in the body
+struct UrlRewriter {
+    regex: Regex,
+    prefix: Vec<u8>,
+    // Bytes at the end that are held back because they could be the start of a match
+    keep: usize,
+    buf: Vec<u8>,
+    // Bytes at the start of the buffer that were already sent, kept to find word boundaries
+    start: usize,
+}
+
+impl UrlRewriter {
+    fn new<'a>(prefix: &str, patterns: impl Iterator<Item = &'a str>) -> Option<Self> {
+        let patterns = patterns.collect::<Vec<_>>();
+        let mut expressions = vec![HTML_URLS.to_owned(), CSS_URLS.to_owned()];
+        expressions.extend(patterns.iter().map(|pattern| regex::escape(pattern)));
+        let longest = patterns
+            .iter()
+            .map(|pattern| pattern.len())
+            .fold(MAX_URL_MATCH, usize::max);
+
+        let regex = match Regex::new(&expressions.join("|")) {
+            Ok(regex) => regex,
+            Err(err) => {
+                eprintln!("Failed to build the rewrite patterns: {}", err);
+                return None;
+            }
+        };
+
+        Some(Self {
+            regex,
+            prefix: prefix.as_bytes().to_vec(),
+            keep: longest + prefix.len() + 1,
+            buf: Vec::new(),
+            start: 0,
+        })
+    }
+
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(data);
+        self.rewrite(false)
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        self.rewrite(true)
+    }
+
+    fn rewrite(&mut self, last: bool) -> Vec<u8> {
+        // Matches starting after the cut have to wait for more data
+        let cut = match last {
+            true => self.buf.len(),
+            false => self.buf.len().saturating_sub(self.keep),
+        };
+        if cut <= self.start {
+            return Vec::new();
+        }
+
+        let mut out = Vec::with_capacity(cut - self.start + self.prefix.len());
+        let mut pos = self.start;
+        while let Some(found) = self.regex.find_at(&self.buf, pos) {
+            if found.start() >= cut {
+                break;
+            }
+
+            // Every match ends with or contains the slash the prefix goes in front of
+            let slash = found.start()
+                + found
+                    .as_bytes()
+                    .iter()
+                    .position(|&b| b == b'/')
+                    .unwrap_or_default();
+            out.extend_from_slice(&self.buf[pos..slash]);
+            if self.needs_prefix(slash) {
+                out.extend_from_slice(&self.prefix);
+            }
+            out.extend_from_slice(&self.buf[slash..found.end()]);
+            pos = found.end();
+        }
+        let end = cut.max(pos);
+        out.extend_from_slice(&self.buf[pos..end]);
+
+        // Drop what was sent, except for the byte before the rest
+        let context = end.min(1);
+        self.buf.drain(..end - context);
+        self.start = context;
+
+        out
+    }
+
+    fn needs_prefix(&self, slash: usize) -> bool {
+        let rest = &self.buf[slash..];
+
+        // Protocol relative urls are for other hosts
+        if rest.get(1) == Some(&b'/') {
+            return false;
+        }
+
+        // Urls that already have the prefix are left alone
+        match rest.strip_prefix(self.prefix.as_slice()) {
+            Some(after) => after
+                .first()
+                .is_some_and(|b| b.is_ascii_alphanumeric() || b"-_.~%".contains(b)),
+            None => true,
+        }
+    }
+}
+
+enum Decoder {
+    Identity,
+    Gzip(Box<GzDecoder<Vec<u8>>>),
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+}
+
+impl Decoder {
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Identity => Ok(data.to_vec()),
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                Ok(mem::take(decoder.get_mut()))
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.write_all(data)?;
+                Ok(mem::take(decoder.get_mut()))
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        match mem::replace(self, Decoder::Identity) {
+            Decoder::Identity => Ok(Vec::new()),
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Brotli(decoder) => decoder.into_inner().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The brotli body is incomplete",
+                )
+            }),
+        }
+    }
+}
+
+enum Encoder {
+    Identity,
+    Gzip(Box<GzEncoder<Vec<u8>>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    // Flush after every write, so the client gets the body while it streams
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        match self {
+            Encoder::Identity => Ok(data.to_vec()),
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<Vec<u8>> {
+        match mem::replace(self, Encoder::Identity) {
+            Encoder::Identity => Ok(Vec::new()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"<a href=/docs>Docs</a>
+<img SRC='/img/logo.png'>
+<link href="/app/style.css"><a href="/app">Home</a><a href="/apple">Apple</a>
+<script src="//cdn.example.com/lib.js"></script>
+<style>body { background: url( "/bg.png") } .x { background: url(/x.png) }</style>
+<a href="https://example.com/abs">Abs</a><a href="relative/path">Rel</a>
+<script>const conf = { base: "/api" };</script>"#;
+
+    const REWRITTEN: &str = r#"<a href=/app/docs>Docs</a>
+<img SRC='/app/img/logo.png'>
+<link href="/app/style.css"><a href="/app">Home</a><a href="/app/apple">Apple</a>
+<script src="//cdn.example.com/lib.js"></script>
+<style>body { background: url( "/app/bg.png") } .x { background: url(/app/x.png) }</style>
+<a href="https://example.com/abs">Abs</a><a href="relative/path">Rel</a>
+<script>const conf = { base: "/app/api" };</script>"#;
+
+    fn rewriter() -> UrlRewriter {
+        UrlRewriter::new("/app", [r#"base: "/"#].into_iter()).unwrap()
+    }
+
+    #[test]
+    fn rewrites_whole_document() {
+        let mut rewriter = rewriter();
+        let mut out = rewriter.push(DOCUMENT.as_bytes());
+        out.extend(rewriter.finish());
+
+        assert_eq!(String::from_utf8(out).unwrap(), REWRITTEN);
+    }
+
+    #[test]
+    fn rewrites_across_every_chunk_boundary() {
+        let document = DOCUMENT.as_bytes();
+        for split in 0..=document.len() {
+            let mut rewriter = rewriter();
+            let mut out = rewriter.push(&document[..split]);
+            out.extend(rewriter.push(&document[split..]));
+            out.extend(rewriter.finish());
+
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                REWRITTEN,
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    fn response(status: StatusCode) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CONTENT_LENGTH, DOCUMENT.len())
+            .header(ETAG, "\"v1\"")
+            .header("content-md5", "Q2hlY2sgSW50ZWdyaXR5IQ==")
+            .body(Body::from(DOCUMENT))
+            .unwrap()
+    }
+
+    fn proxy() -> Proxy {
+        Proxy {
+            path_prefix: "/app".to_string(),
+            rewrite_body: true,
+            rewrite_patterns: r#"base: "/"#.to_string(),
+            ..Proxy::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn rewritten_responses_drop_length_checksum_and_strong_etag() {
+        let res = rewrite_response_body(&proxy(), response(StatusCode::OK));
+        let headers = res.headers();
+        assert!(headers.get(CONTENT_LENGTH).is_none());
+        assert!(headers.get("content-md5").is_none());
+        assert_eq!(headers[ETAG], "W/\"v1\"");
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, REWRITTEN);
+    }
+
+    #[test]
+    fn weak_etags_stay_the_same() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("W/\"v1\""));
+        weaken_etag(&mut headers);
+        assert_eq!(headers[ETAG], "W/\"v1\"");
+    }
+
+    #[test]
+    fn responses_without_a_body_are_left_alone() {
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_MODIFIED] {
+            let res = rewrite_response_body(&proxy(), response(status));
+            let headers = res.headers();
+            assert_eq!(headers[CONTENT_LENGTH], DOCUMENT.len().to_string().as_str());
+            assert_eq!(headers[ETAG], "\"v1\"");
+            assert!(headers.get("content-md5").is_some());
+        }
+    }
+
+    #[test]
+    fn rewrites_byte_by_byte() {
+        let mut rewriter = rewriter();
+        let mut out = Vec::new();
+        for byte in DOCUMENT.as_bytes() {
+            out.extend(rewriter.push(&[*byte]));
+        }
+        out.extend(rewriter.finish());
+
+        assert_eq!(String::from_utf8(out).unwrap(), REWRITTEN);
+    }
+}
@@ -0,0 +1,23 @@
+use crate::*;
+
+use hyper::{Body, Response, StatusCode};
+use tera::Tera;
+
+// Render the error page with the given status and message
+pub fn error_page(tera: &Tera, status: StatusCode, msg: &str) -> Response<Body> {
+    // Create the context
+    let mut context = tera::Context::new();
+    context.insert("title", status.canonical_reason().unwrap_or("Error"));
+    context.insert("status_code", &status.as_u16());
+    context.insert("status", "error");
+    context.insert("msg", msg);
+
+    // Render the error page
+    let error_page = check_err!(tera.render("error.tera.html", &context));
+
+    // Send the error page
+    Response::builder()
+        .status(status)
+        .body(Body::from(error_page))
+        .unwrap()
+}
@@ -0,0 +1,104 @@
+use crate::*;
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+
+// Names of the headers telling upstreams who is logged in (user, admin, groups)
+pub fn identity_header_names(conf: &Config) -> [HeaderName; 3] {
+    [RemoteUserHeader, RemoteAdminHeader, RemoteGroupsHeader].map(|option| {
+        check_err!(HeaderName::from_bytes(
+            check_err!(conf.get(option)).as_bytes()
+        ))
+    })
+}
+
+// The headers telling who is logged in
+pub fn identity_headers(conf: &Config, session: &Session) -> Vec<(HeaderName, HeaderValue)> {
+    let [user, admin, groups] = identity_header_names(conf);
+
+    [
+        (user, session.user.to_owned()),
+        (admin, session.admin.to_string()),
+        (groups, session.groups.join(",")),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, HeaderValue::from_str(&value).ok()?)))
+    .collect()
+}
+
+// Replace any identity headers the client sent itself with the ones of the session, so they can't be spoofed
+// Upstreams behind CGI, FastCGI and many servers read X_Remote_User as X-Remote-User, so those go as well
+pub fn set_identity_headers(conf: &Config, headers: &mut HeaderMap, session: &Session) {
+    let names = identity_header_names(conf);
+    let spoofed = headers
+        .keys()
+        .filter(|name| {
+            let normalized = name.as_str().replace('_', "-");
+            names
+                .iter()
+                .any(|identity| identity.as_str().eq_ignore_ascii_case(&normalized))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in spoofed {
+        headers.remove(name);
+    }
+    headers.extend(identity_headers(conf, session));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn session() -> Session {
+        let now = Utc::now();
+        Session {
+            admin: false,
+            user: "alice".to_string(),
+            groups: vec!["dev".to_string()],
+            id: String::new(),
+            created_time: now,
+            renew_time: now,
+            expire_time: now,
+        }
+    }
+
+    #[test]
+    fn replaces_spoofed_identity_headers() {
+        let conf = Config::for_tests(&[]);
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-remote-user", "admin"),
+            ("X_Remote_User", "admin"),
+            ("x_remote-admin", "true"),
+            ("X-REMOTE_GROUPS", "admins"),
+            ("x-remote-userx", "kept"),
+        ] {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_static(value),
+            );
+        }
+
+        set_identity_headers(&conf, &mut headers, &session());
+
+        let values = |name: &str| {
+            headers
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values("x-remote-user"), ["alice"]);
+        assert_eq!(values("x-remote-admin"), ["false"]);
+        assert_eq!(values("x-remote-groups"), ["dev"]);
+        assert!(values("x_remote_user").is_empty());
+        assert!(values("x_remote-admin").is_empty());
+        assert!(values("x-remote_groups").is_empty());
+        assert_eq!(values("x-remote-userx"), ["kept"]);
+        assert_eq!(headers.len(), 4);
+    }
+}
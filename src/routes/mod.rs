@@ -1,10 +1,26 @@
-pub use admin::admin_page;
+pub use account::{account_page, account_req};
+pub use admin::{admin_delete, admin_invite, admin_page, admin_unlock, admin_update};
 pub use auth::{
+    invite::{invite_page, invite_req},
     login::{login_page, login_req},
     logout::logout,
+    passkey::{passkey_login, passkey_login_options, passkey_register, passkey_register_options},
+    setup::{setup_page, setup_req},
+    two_factor::{two_factor_page, two_factor_req},
 };
+pub use error::error_page;
+pub use identity::identity_header_names;
 pub use proxy::proxy;
+pub use verify::verify;
 
+pub mod account;
 pub mod admin;
+pub mod api;
 pub mod auth;
+pub mod body;
+pub mod error;
+pub mod identity;
 pub mod proxy;
+pub mod rewrite;
+pub mod upgrade;
+pub mod verify;
@@ -1,48 +1,110 @@
-use crate::*;
+use crate::{
+    routes::{
+        body::{limit_accept_encoding, rewrite_response_body},
+        identity::set_identity_headers,
+        rewrite::rewrite_response_headers,
+        upgrade::{is_upgrade, splice},
+    },
+    *,
+};
 
 use axum::{
-    extract::State,
-    response::{Redirect, Response},
+    body::boxed,
+    extract::{ConnectInfo, State},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONNECTION, COOKIE, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION,
+        RETRY_AFTER, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    Body, HeaderMap, Method, Request, StatusCode, Uri,
 };
-use axum_extra::extract::CookieJar;
-use hyper::{Body, Request, StatusCode, Uri};
+use std::net::SocketAddr;
+use urlencoding::encode;
 
 pub async fn proxy(
     State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     jar: CookieJar,
     mut req: Request<Body>,
-) -> Result<Response<Body>, Redirect> {
+) -> Result<Response, Redirect> {
     // Initlize variables
-    let (sessions, client, conf, _, _) = app_state.extract();
+    let (sessions, client, conf, tera, db) = app_state.extract();
     let special_route = check_err!(conf.get(SpecialRoute));
-    let cookie_name = check_err!(conf.get(CookieName));
 
-    // Get cookie
-    let cookie = match jar.get(&cookie_name) {
-        Some(cookie) => cookie,
+    // Check the session, or send the user to the login page
+    let (session, renewed_cookie) = match check_session(&sessions, &conf, &jar).await {
+        Some(session) => session,
+        None => return Err(login_redirect(&special_route, &req)),
+    };
+
+    // Find the proxy route for the requested virtual host
+    let host = match request_host(&req) {
+        Some(host) => host,
         None => {
-            // Redirect to login page
-            return Err(Redirect::to(&format!("{}/login", special_route)));
+            return Ok(error_page(
+                &tera,
+                StatusCode::BAD_REQUEST,
+                "The request did not say which site it is for.",
+            )
+            .into_response())
         }
     };
-
-    // Get session
-    let mut session = match sessions.get(cookie.value()).await {
-        Some(session) => session,
+    let path = req.uri().path();
+    let proxy = match db.get_proxies_for_host(&host).await {
+        Ok(proxies) => select_proxy(proxies, path, &special_route),
+        Err(err) => {
+            eprintln!("Failed to look up proxy route for {}: {}", host, err);
+            return Ok(error_page(
+                &tera,
+                StatusCode::BAD_GATEWAY,
+                "Oops! Something went wrong while looking up this site.",
+            )
+            .into_response());
+        }
+    };
+    let proxy = match proxy {
+        Some(proxy) => proxy,
         None => {
-            // Redirect to login page
-            return Err(Redirect::to(&format!("{}/login", special_route)));
+            return Ok(error_page(
+                &tera,
+                StatusCode::NOT_FOUND,
+                &format!("There is no site configured for {}{}.", host, path),
+            )
+            .into_response())
         }
     };
 
-    // Validate session
-    if session.expired() {
-        // Redirect to login page
-        return Err(Redirect::to(&format!("{}/login", special_route)));
-    }
-
-    // Renew session
-    session.renew();
+    // Pick the upstream for this request, out of the ones that are up
+    let (balancer, health) = (app_state.balancer(), app_state.health());
+    let targets = health.available(&proxy).await;
+    let ip = client_ip(&conf, req.headers(), addr.ip());
+    let target = match balancer.pick(&proxy, &targets, ip, &session.user) {
+        Some(target) => target,
+        None if !proxy.targets.is_empty() => {
+            let mut res = error_page(
+                &tera,
+                StatusCode::SERVICE_UNAVAILABLE,
+                &format!(
+                    "The site {} is down for maintenance. Please try again in a moment.",
+                    proxy.name
+                ),
+            );
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("30"));
+            return Ok(res.into_response());
+        }
+        None => {
+            return Ok(error_page(
+                &tera,
+                StatusCode::BAD_GATEWAY,
+                &format!("The site {} has no upstreams.", proxy.name),
+            )
+            .into_response())
+        }
+    };
 
     // Get the path
     let path = req.uri().path();
@@ -53,26 +115,471 @@ pub async fn proxy(
         .unwrap_or(path);
 
     // Create uri
-    let uri = format!("https://example.com{}", path_query);
+    let uri = match proxy.uri(&target, path_query).parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => {
+            return Ok(error_page(
+                &tera,
+                StatusCode::BAD_GATEWAY,
+                &format!("The site {} is misconfigured.", proxy.name),
+            )
+            .into_response())
+        }
+    };
 
-    // Make the Host header match the new uri
-    // IDK: if this is necessary when using this for local network requests but it is for the requests to external websites
-    let host = uri.replace("https://", "").replace("http://", "");
-    let host = host.split('/').next().unwrap();
-    req.headers_mut().insert("Host", host.parse().unwrap());
+    // Headers for the connection to proxrs don't belong on the connection to the upstream
+    let upgrade = is_upgrade(&req);
+    strip_hop_by_hop(req.headers_mut(), upgrade);
+
+    // Tell the upstream who is logged in, without handing it the session token
+    set_identity_headers(&conf, req.headers_mut(), &session);
+    strip_proxrs_cookies(&conf, req.headers_mut());
+
+    // Pages that get rewritten have to come in an encoding the rewriter can read
+    let rewrite_body = proxy.rewrite_body && req.method() != Method::HEAD;
+    if rewrite_body {
+        limit_accept_encoding(req.headers_mut());
+    }
+
+    // Tell the upstream where the request came from, before the Host header is replaced
+    set_forwarded_headers(&conf, req.headers_mut(), addr.ip());
+
+    // Make the Host header match the upstream
+    req.headers_mut()
+        .insert(HOST, target.authority().parse().unwrap());
 
     // Set the new uri
-    *req.uri_mut() = uri.parse::<Uri>().unwrap();
+    *req.uri_mut() = uri;
+
+    // Take the client connection of an upgrade, it is handed over once the upstream agrees
+    let client_upgrade = match upgrade {
+        true => Some(hyper::upgrade::on(&mut req)),
+        false => None,
+    };
 
-    // Do the request
+    // Do the request, counting it as a connection to the upstream until the response is sent
+    let connection = balancer.connect(&target);
     let res = client.request(req).await;
 
-    // Return the response
+    // Return the response, server errors and unreachable upstreams count against their health
     match res {
-        Ok(res) => Ok(res),
-        Err(err) => Ok(Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(Body::from(format!("Error: {}", err)))
-            .unwrap()),
+        Ok(mut res) => {
+            match res.status().is_server_error() {
+                true => {
+                    let reason = format!("answered {}", res.status());
+                    health.failed(&proxy, &target, reason).await;
+                }
+                false => health.passed(&proxy, &target, false).await,
+            }
+
+            let switching = res.status() == StatusCode::SWITCHING_PROTOCOLS;
+            strip_hop_by_hop(res.headers_mut(), switching);
+            rewrite_response_headers(&proxy, res.headers_mut());
+            if rewrite_body {
+                res = rewrite_response_body(&proxy, res);
+            }
+
+            // Connect both sides after the 101 response reaches the client
+            let mut res = match (switching, client_upgrade) {
+                (true, Some(client_upgrade)) => {
+                    let upstream_upgrade = hyper::upgrade::on(&mut res);
+                    splice(&conf, client_upgrade, upstream_upgrade, connection);
+                    res.map(boxed)
+                }
+                _ => res.map(|body| boxed(connection.hold_until_sent(body))),
+            };
+
+            if let Some(cookie) = renewed_cookie {
+                res.headers_mut()
+                    .append(SET_COOKIE, cookie.encoded().to_string().parse().unwrap());
+            }
+
+            Ok(res)
+        }
+        Err(err) => {
+            eprintln!("Failed to reach upstream {}: {}", target.authority(), err);
+            health.failed(&proxy, &target, err.to_string()).await;
+            Ok(error_page(
+                &tera,
+                StatusCode::BAD_GATEWAY,
+                &format!("The site {} is not reachable right now.", proxy.name),
+            )
+            .into_response())
+        }
+    }
+}
+
+// Get the valid session of the session cookie, with a cookie to send back when the session was renewed
+pub(crate) async fn check_session(
+    sessions: &Sessions,
+    conf: &Config,
+    jar: &CookieJar,
+) -> Option<(Session, Option<Cookie<'static>>)> {
+    // Get cookie
+    let cookie_name = check_err!(conf.get(CookieName));
+    let cookie = jar.get(&cookie_name)?;
+
+    // Get session
+    let mut session = sessions.get(cookie.value()).await?;
+
+    // Validate session
+    if session.expired() {
+        return None;
+    }
+
+    // Renew session, the browser gets the new expire time with the response
+    let renewed_cookie = match sessions.renew(&mut session, conf).await {
+        Ok(true) => Some(session_cookie(conf, cookie.value().to_owned(), &session)),
+        Ok(false) => None,
+        Err(err) => {
+            eprintln!("Failed to renew session: {}", err);
+            None
+        }
+    };
+
+    Some((session, renewed_cookie))
+}
+
+// Headers that only concern a single connection (RFC 9110 section 7.6.1)
+const HOP_BY_HOP: [HeaderName; 8] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+// Remove the hop-by-hop headers, including the ones listed in Connection and any Proxy-* header
+// A protocol upgrade is passed on when keep_upgrade is set and the headers ask for one
+fn strip_hop_by_hop(headers: &mut HeaderMap, keep_upgrade: bool) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+
+    // Remember the upgrade before its headers are removed
+    let upgrade = match keep_upgrade && listed.iter().any(|name| name == "upgrade") {
+        true => headers.get_all(UPGRADE).iter().cloned().collect::<Vec<_>>(),
+        false => Vec::new(),
+    };
+
+    for name in listed {
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    let proxy_headers = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in proxy_headers {
+        headers.remove(name);
+    }
+
+    if !upgrade.is_empty() {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        for value in upgrade {
+            headers.append(UPGRADE, value);
+        }
+    }
+}
+
+// Remove the cookies of proxrs from the Cookie headers, keeping the cookies of the site itself
+fn strip_proxrs_cookies(conf: &Config, headers: &mut HeaderMap) {
+    let names = proxrs_cookie_names(conf);
+    let cookies = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .map(str::trim)
+                .filter(|pair| {
+                    let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+                    !pair.is_empty() && !names.iter().any(|proxrs| proxrs == name.trim())
+                })
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+
+    headers.remove(COOKIE);
+    for value in cookies {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.append(COOKIE, value);
+        }
+    }
+}
+
+// Send the user to the login page, remembering the page to return to afterwards
+fn login_redirect(special_route: &str, req: &Request<Body>) -> Redirect {
+    let next = req
+        .uri()
+        .path_and_query()
+        .map(|v| v.as_str())
+        .unwrap_or("/");
+
+    Redirect::to(&format!("{}/login?next={}", special_route, encode(next)))
+}
+
+// Pick the route with the longest matching path prefix, preferring routes for the exact host over wildcard routes
+// Paths inside the special route are never proxied
+pub(crate) fn select_proxy(proxies: Vec<Proxy>, path: &str, special_route: &str) -> Option<Proxy> {
+    let special_route = special_route.trim_end_matches('/');
+    if path == special_route || path.starts_with(&format!("{}/", special_route)) {
+        return None;
+    }
+
+    proxies
+        .into_iter()
+        .filter(|proxy| !proxy.collides_with(special_route))
+        .filter(|proxy| proxy.matches_path(path))
+        .max_by_key(|proxy| (proxy.prefix().len(), !proxy.is_wildcard()))
+}
+
+// Get the virtual host name the request is for (without the port)
+pub(crate) fn request_host(req: &Request<Body>) -> Option<String> {
+    // Prefer the Host header, fall back to the uri authority (HTTP/2)
+    let host = match req.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?.to_owned(),
+        None => req.uri().host()?.to_owned(),
+    };
+
+    // Strip the port, keeping bracketed ipv6 addresses intact
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => &host[..],
+    };
+
+    // Check if the host is empty
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(id: i64, name: &str, path_prefix: &str) -> Proxy {
+        Proxy {
+            id,
+            name: name.to_string(),
+            path_prefix: path_prefix.to_string(),
+            is_enabled: true,
+            ..Proxy::default()
+        }
+    }
+
+    fn request(host: Option<&str>, uri: &str) -> Request<Body> {
+        let mut req = Request::get(uri);
+        if let Some(host) = host {
+            req = req.header(HOST, host);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn selects_the_longest_matching_prefix() {
+        let routes = || {
+            vec![
+                route(1, "app.local", "/"),
+                route(2, "app.local", "/app"),
+                route(3, "app.local", "/app/admin/"),
+                route(4, "*", "/app/admin/"),
+                route(5, "*", "/app/admin/reports"),
+                route(6, "app.local", "/proxrs-app"),
+            ]
+        };
+
+        let cases = [
+            ("/", Some(1)),
+            ("/app", Some(2)),
+            ("/app/", Some(2)),
+            ("/app/page", Some(2)),
+            ("/application", Some(1)),
+            ("/apps/page", Some(1)),
+            ("/app/admin", Some(3)),
+            ("/app/admin/", Some(3)),
+            ("/app/admin/users", Some(3)),
+            ("/app/administration", Some(2)),
+            ("/app/admin/reports/1", Some(5)),
+            ("/proxrs-app/page", Some(6)),
+            ("/proxrs", None),
+            ("/proxrs/login", None),
+        ];
+        for (path, expected) in cases {
+            let selected = select_proxy(routes(), path, "/proxrs/").map(|proxy| proxy.id);
+            assert_eq!(selected, expected, "path {}", path);
+        }
+    }
+
+    #[test]
+    fn strips_the_prefix_down_to_the_root() {
+        let target = Target {
+            host: "127.0.0.1".to_string(),
+            port: 3000,
+        };
+        let cases = [
+            ("/app", "/app", "/"),
+            ("/app/", "/app", "/"),
+            ("/app/", "/app/", "/"),
+            ("/app", "/app?page=2", "/?page=2"),
+            ("/app", "/app/docs/?page=2", "/docs/?page=2"),
+            ("/", "/docs", "/docs"),
+        ];
+        for (path_prefix, path_query, expected) in cases {
+            let proxy = Proxy {
+                strip_prefix: true,
+                ..route(1, "app.local", path_prefix)
+            };
+            assert_eq!(
+                proxy.uri(&target, path_query),
+                format!("http://127.0.0.1:3000{}", expected)
+            );
+        }
+    }
+
+    fn header_map(values: &[(&str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            let name = HeaderName::from_bytes(name.as_bytes()).unwrap();
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = header_map(&[
+            ("connection", "keep-alive, X-Trace, x-debug"),
+            ("connection", " Close ,,"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-authorization", "Basic c3Rhbjpwdw=="),
+            ("proxy-connection", "keep-alive"),
+            ("te", "trailers"),
+            ("trailer", "expires"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-trace", "1"),
+            ("x-debug", "on"),
+            ("close", "yes"),
+            ("accept", "text/html"),
+            ("x-request-id", "42"),
+        ]);
+        strip_hop_by_hop(&mut headers, true);
+
+        let mut left = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["accept", "x-request-id"]);
+    }
+
+    #[test]
+    fn keeps_upgrades_that_are_asked_for() {
+        let upgrade = || {
+            header_map(&[
+                ("connection", "Upgrade, x-trace"),
+                ("upgrade", "websocket"),
+                ("x-trace", "1"),
+                ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ])
+        };
+
+        let mut headers = upgrade();
+        strip_hop_by_hop(&mut headers, true);
+        assert_eq!(headers[CONNECTION], "upgrade");
+        assert_eq!(headers[UPGRADE], "websocket");
+        assert!(!headers.contains_key("x-trace"));
+        assert!(headers.contains_key("sec-websocket-key"));
+
+        let mut headers = upgrade();
+        strip_hop_by_hop(&mut headers, false);
+        assert!(!headers.contains_key(CONNECTION));
+        assert!(!headers.contains_key(UPGRADE));
+    }
+
+    #[test]
+    fn strips_only_the_proxrs_cookies() {
+        let conf = Config::for_tests(&[(CookieName, "proxrs")]);
+        let cases: [(&[&str], &[&str]); 5] = [
+            (
+                &["proxrs=session; theme=dark; proxrs-csrf=token; lang=en"],
+                &["theme=dark; lang=en"],
+            ),
+            (
+                &["proxrs-login=pending", "app_session=abc; proxrs=session"],
+                &["app_session=abc"],
+            ),
+            (&["proxrs=session; proxrs-csrf=token"], &[]),
+            (&["proxrs=session", "proxrs-csrf=token;"], &[]),
+            (
+                &["proxrs2=kept; my-proxrs=kept"],
+                &["proxrs2=kept; my-proxrs=kept"],
+            ),
+        ];
+        for (cookies, expected) in cases {
+            let mut headers = HeaderMap::new();
+            for cookie in cookies {
+                headers.append(COOKIE, HeaderValue::from_static(cookie));
+            }
+
+            strip_proxrs_cookies(&conf, &mut headers);
+            let left = headers.get_all(COOKIE).iter().collect::<Vec<_>>();
+            assert_eq!(left, expected, "cookies {:?}", cookies);
+            assert_eq!(headers.contains_key(COOKIE), !expected.is_empty());
+        }
+    }
+
+    #[test]
+    fn login_redirects_remember_the_page() {
+        let cases = [
+            ("/", "/proxrs/login?next=%2F"),
+            (
+                "/app/page?tab=2&q=a%20b",
+                "/proxrs/login?next=%2Fapp%2Fpage%3Ftab%3D2%26q%3Da%2520b",
+            ),
+            ("//evil.com/", "/proxrs/login?next=%2F%2Fevil.com%2F"),
+            (
+                "/%2F%2Fevil.com",
+                "/proxrs/login?next=%2F%252F%252Fevil.com",
+            ),
+        ];
+        for (uri, expected) in cases {
+            let res = login_redirect("/proxrs", &request(Some("app.local"), uri)).into_response();
+            assert_eq!(res.headers()[hyper::header::LOCATION], expected);
+        }
+    }
+
+    #[test]
+    fn reads_the_requested_host() {
+        let cases = [
+            (Some("App.Local"), "/", Some("app.local")),
+            (Some("app.local:3678"), "/", Some("app.local")),
+            (Some("[::1]:3678"), "/", Some("[::1]")),
+            (Some("[::1]"), "/", Some("[::1]")),
+            (Some(":3678"), "/", None),
+            (Some(""), "/", None),
+            (None, "http://app.local:3678/page", Some("app.local")),
+            (None, "/page", None),
+        ];
+        for (host, uri, expected) in cases {
+            let req = request(host, uri);
+            assert_eq!(request_host(&req).as_deref(), expected, "host {:?}", host);
+        }
     }
 }
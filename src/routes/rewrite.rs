@@ -0,0 +1,296 @@
+use crate::*;
+
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_LOCATION, LOCATION, REFRESH, SET_COOKIE},
+    HeaderMap,
+};
+
+// Point the urls and cookies in the response of an upstream back at the public host and route
+pub(crate) fn rewrite_response_headers(proxy: &Proxy, headers: &mut HeaderMap) {
+    for name in [LOCATION, CONTENT_LOCATION] {
+        rewrite_each(headers, name, |value| public_url(proxy, value));
+    }
+    rewrite_each(headers, REFRESH, |value| rewrite_refresh(proxy, value));
+    rewrite_each(headers, SET_COOKIE, |value| rewrite_cookie(proxy, value));
+}
+
+// Replace every value of a header the rewrite applies to, leaving the others alone
+fn rewrite_each(
+    headers: &mut HeaderMap,
+    name: HeaderName,
+    rewrite: impl Fn(&str) -> Option<String>,
+) {
+    if !headers.contains_key(&name) {
+        return;
+    }
+
+    let values = headers
+        .get_all(&name)
+        .iter()
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(&rewrite)
+                .and_then(|rewritten| HeaderValue::from_str(&rewritten).ok())
+                .unwrap_or_else(|| value.clone())
+        })
+        .collect::<Vec<_>>();
+
+    headers.remove(&name);
+    for value in values {
+        headers.append(&name, value);
+    }
+}
+
+// Turn a url of the upstream into the path the browser uses for it
+// Urls of the upstream origin become relative, so they stay on the host and scheme the browser is using
+fn public_url(proxy: &Proxy, url: &str) -> Option<String> {
+    let url = url.trim();
+    let path = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+                return None;
+            }
+            let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+            if !is_upstream(proxy, &rest[..end]) {
+                return None;
+            }
+            match &rest[end..] {
+                path if path.starts_with('/') => path.to_owned(),
+                path => format!("/{}", path),
+            }
+        }
+        // Paths on the upstream only change when the route strips its prefix
+        None if url.starts_with('/') && !url.starts_with("//") && proxy.strip_prefix => {
+            url.to_owned()
+        }
+        None => return None,
+    };
+
+    match proxy.strip_prefix {
+        true => Some(format!("{}{}", proxy.prefix(), path)),
+        false => Some(path),
+    }
+}
+
+// Check if the authority of a url (with optional user info and port) is one of the upstreams
+fn is_upstream(proxy: &Proxy, authority: &str) -> bool {
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+
+    proxy.targets.iter().any(|target| {
+        authority.eq_ignore_ascii_case(&target.authority())
+            || authority.eq_ignore_ascii_case(&target.to_string())
+    })
+}
+
+fn is_upstream_host(proxy: &Proxy, host: &str) -> bool {
+    proxy
+        .targets
+        .iter()
+        .any(|target| host.eq_ignore_ascii_case(&target.host))
+}
+
+// Rewrite the url in a Refresh header like "5; url=http://127.0.0.1:3000/next"
+fn rewrite_refresh(proxy: &Proxy, value: &str) -> Option<String> {
+    let (delay, rest) = value.split_once(';')?;
+    let (key, url) = rest.trim().split_once('=')?;
+    if !key.trim().eq_ignore_ascii_case("url") {
+        return None;
+    }
+    let url = url.trim().trim_matches(|c| c == '\'' || c == '"');
+
+    Some(format!("{}; url={}", delay.trim(), public_url(proxy, url)?))
+}
+
+// Drop a cookie domain that names the upstream, and move the cookie path under the route prefix
+fn rewrite_cookie(proxy: &Proxy, value: &str) -> Option<String> {
+    let mut parts = value.split(';').map(str::trim);
+    let mut cookie = vec![parts.next()?.to_owned()];
+    let mut changed = false;
+
+    for attribute in parts {
+        let (key, val) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let key = key.trim();
+
+        // Without a domain the cookie belongs to the public host the browser used
+        if key.eq_ignore_ascii_case("domain")
+            && is_upstream_host(proxy, val.trim().trim_start_matches('.'))
+        {
+            changed = true;
+            continue;
+        }
+
+        if key.eq_ignore_ascii_case("path") && proxy.strip_prefix {
+            let path = val.trim();
+            if path.starts_with('/') {
+                cookie.push(format!("Path={}{}", proxy.prefix(), path));
+                changed = true;
+                continue;
+            }
+        }
+
+        cookie.push(attribute.to_owned());
+    }
+
+    match changed {
+        true => Some(cookie.join("; ")),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A route that strips its prefix and one that doesn't
+    fn routes() -> [Proxy; 2] {
+        let proxy = |strip_prefix| Proxy {
+            name: "app.local".to_string(),
+            targets: vec![
+                Target {
+                    host: "127.0.0.1".to_string(),
+                    port: 3000,
+                },
+                Target {
+                    host: "backend.internal".to_string(),
+                    port: 80,
+                },
+            ],
+            path_prefix: "/app/".to_string(),
+            strip_prefix,
+            ..Proxy::default()
+        };
+        [proxy(true), proxy(false)]
+    }
+
+    type Cases<'a> = [(&'a str, Option<&'a str>, Option<&'a str>)];
+
+    fn check(cases: &Cases, rewrite: fn(&Proxy, &str) -> Option<String>) {
+        let [stripped, kept] = routes();
+        for (value, expected_stripped, expected_kept) in cases {
+            assert_eq!(
+                rewrite(&stripped, value).as_deref(),
+                *expected_stripped,
+                "{}",
+                value
+            );
+            assert_eq!(
+                rewrite(&kept, value).as_deref(),
+                *expected_kept,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rewrites_locations() {
+        check(
+            &[
+                (
+                    "http://127.0.0.1:3000/login?next=%2F",
+                    Some("/app/login?next=%2F"),
+                    Some("/login?next=%2F"),
+                ),
+                (
+                    "HTTPS://Backend.Internal/next",
+                    Some("/app/next"),
+                    Some("/next"),
+                ),
+                (
+                    "http://backend.internal:80/next",
+                    Some("/app/next"),
+                    Some("/next"),
+                ),
+                ("http://stan:pw@127.0.0.1:3000", Some("/app/"), Some("/")),
+                (
+                    "http://127.0.0.1:3000?tab=2",
+                    Some("/app/?tab=2"),
+                    Some("/?tab=2"),
+                ),
+                (" /login ", Some("/app/login"), None),
+                ("http://127.0.0.1:3001/login", None, None),
+                ("https://example.com/login", None, None),
+                ("ftp://127.0.0.1:3000/file", None, None),
+                ("//127.0.0.1:3000/login", None, None),
+                ("login", None, None),
+            ],
+            public_url,
+        );
+    }
+
+    #[test]
+    fn rewrites_refresh_urls() {
+        check(
+            &[
+                (
+                    "5; url=http://127.0.0.1:3000/next",
+                    Some("5; url=/app/next"),
+                    Some("5; url=/next"),
+                ),
+                ("0;URL='/next'", Some("0; url=/app/next"), None),
+                ("5", None, None),
+                ("5; url=https://example.com/", None, None),
+                ("5; to=/next", None, None),
+            ],
+            rewrite_refresh,
+        );
+    }
+
+    #[test]
+    fn rewrites_cookie_paths_and_domains() {
+        check(
+            &[
+                (
+                    "sid=1; Path=/; HttpOnly",
+                    Some("sid=1; Path=/app/; HttpOnly"),
+                    None,
+                ),
+                (
+                    "sid=1; Domain=127.0.0.1; path=/admin",
+                    Some("sid=1; Path=/app/admin"),
+                    Some("sid=1; path=/admin"),
+                ),
+                (
+                    "sid=1; domain=.Backend.Internal",
+                    Some("sid=1"),
+                    Some("sid=1"),
+                ),
+                (
+                    "sid=1; Domain=example.com; Path=/",
+                    Some("sid=1; Domain=example.com; Path=/app/"),
+                    None,
+                ),
+                ("sid=1; Path=relative", None, None),
+                ("sid=1", None, None),
+            ],
+            rewrite_cookie,
+        );
+    }
+
+    #[test]
+    fn rewrites_every_header_value() {
+        let [stripped, _] = routes();
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("http://127.0.0.1:3000/"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("a=1; Path=/"));
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("b=2; Domain=example.com"),
+        );
+        headers.append(SET_COOKIE, HeaderValue::from_static("c=3; Path=/x"));
+
+        rewrite_response_headers(&stripped, &mut headers);
+        assert_eq!(headers[LOCATION], "/app/");
+        let cookies = headers.get_all(SET_COOKIE).iter().collect::<Vec<_>>();
+        assert_eq!(
+            cookies,
+            [
+                "a=1; Path=/app/",
+                "b=2; Domain=example.com",
+                "c=3; Path=/app/x"
+            ]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::*;
+
+use hyper::{
+    header::{CONNECTION, UPGRADE},
+    upgrade::{OnUpgrade, Upgraded},
+    Body, Request, Version,
+};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::sleep,
+};
+
+// Size of the buffers used to copy data between the client and the upstream
+const BUFFER_SIZE: usize = 8192;
+
+// Check if the request asks to switch protocols (e.g. to a WebSocket), which only HTTP/1.1 can do
+pub(crate) fn is_upgrade(req: &Request<Body>) -> bool {
+    let connection_upgrade = req
+        .headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("upgrade"));
+
+    req.version() == Version::HTTP_11 && connection_upgrade && req.headers().contains_key(UPGRADE)
+}
+
+// Connect the client and the upstream once both have switched protocols, in the background
+// The connection keeps counting for the upstream while the sides are connected
+pub(crate) fn splice(
+    conf: &Config,
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    connection: ActiveConnection,
+) {
+    let idle_timeout = check_err!(check_err!(conf.get(UpgradeIdleTimeout)).parse::<u64>());
+    let idle_timeout = Duration::from_secs(idle_timeout);
+
+    tokio::spawn(async move {
+        let authority = connection.authority().to_owned();
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Ok(connections) => connections,
+            Err(err) => {
+                eprintln!("Failed to upgrade connection to {}: {}", authority, err);
+                return;
+            }
+        };
+
+        if let Err(err) = copy_until_idle(client, upstream, idle_timeout).await {
+            eprintln!("Upgraded connection to {} failed: {}", authority, err);
+        }
+    });
+}
+
+// Copy data both ways until either side closes or nothing was sent for the idle timeout
+async fn copy_until_idle(
+    mut client: Upgraded,
+    mut upstream: Upgraded,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let mut client_buf = vec![0; BUFFER_SIZE];
+    let mut upstream_buf = vec![0; BUFFER_SIZE];
+
+    loop {
+        tokio::select! {
+            read = client.read(&mut client_buf) => match read? {
+                0 => break,
+                n => upstream.write_all(&client_buf[..n]).await?,
+            },
+            read = upstream.read(&mut upstream_buf) => match read? {
+                0 => break,
+                n => client.write_all(&upstream_buf[..n]).await?,
+            },
+            _ = sleep(idle_timeout) => break,
+        }
+    }
+
+    // Let both sides know the connection is over
+    let _ = client.shutdown().await;
+    let _ = upstream.shutdown().await;
+
+    Ok(())
+}
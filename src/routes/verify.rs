@@ -0,0 +1,57 @@
+use crate::{
+    routes::{identity::identity_headers, proxy::check_session},
+    *,
+};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::CookieJar;
+use hyper::{header::SET_COOKIE, Body, Request, StatusCode};
+use urlencoding::encode;
+
+// Header Traefik sends the path and query of the original request in
+const FORWARDED_URI: &str = "x-forwarded-uri";
+
+// Tell an edge proxy (nginx auth_request, Traefik ForwardAuth) if the request is logged in and who made it
+pub async fn verify(
+    State(app_state): State<AppState>,
+    jar: CookieJar,
+    req: Request<Body>,
+) -> Response {
+    // Initialize variables
+    let (sessions, _, conf, _, _) = app_state.extract();
+    let special_route = check_err!(conf.get(SpecialRoute));
+
+    // Check the session exactly like proxied requests are checked
+    let (session, renewed_cookie) = match check_session(&sessions, &conf, &jar).await {
+        Some(session) => session,
+        None => return unauthorized(&special_route, &req),
+    };
+
+    // Answer with who the user is
+    let mut res = Response::builder().status(StatusCode::OK);
+    for (name, value) in identity_headers(&conf, &session) {
+        res = res.header(name, value);
+    }
+    if let Some(cookie) = renewed_cookie {
+        res = res.header(SET_COOKIE, cookie.encoded().to_string());
+    }
+    res.body(Body::empty()).unwrap().into_response()
+}
+
+// Traefik sends the answer on to the browser, so it can redirect to the login page
+// nginx only understands 401 and 403 from auth_request and redirects itself
+fn unauthorized(special_route: &str, req: &Request<Body>) -> Response {
+    match req
+        .headers()
+        .get(FORWARDED_URI)
+        .and_then(|uri| uri.to_str().ok())
+    {
+        Some(uri) => {
+            Redirect::to(&format!("{}/login?next={}", special_route, encode(uri))).into_response()
+        }
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
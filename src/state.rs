@@ -5,6 +5,13 @@ use tera::Tera;
 #[derive(Clone)]
 pub struct AppState {
     sessions: Sessions,
+    setup: SetupToken,
+    lockouts: Lockouts,
+    pending_logins: PendingLogins,
+    passkey_challenges: PasskeyChallenges,
+    totp_enrollments: TotpEnrollments,
+    balancer: Balancer,
+    health: Health,
     client: Client,
     conf: Config,
     tera: Tera,
@@ -18,7 +25,30 @@ impl AppState {
         let db = check_err!(Db::new(db_file).await);
 
         // Initialize the sessions
-        let sessions = Sessions::new();
+        let sessions = check_err!(Sessions::new(db.clone()).await);
+
+        // Periodically remove expired sessions
+        let reap_interval = check_err!(check_err!(conf.get(SessionReapInterval)).parse::<u64>());
+        sessions.spawn_reaper(std::time::Duration::from_secs(reap_interval.max(1)));
+
+        // Create the setup token when this is the first run
+        let setup = SetupToken::new(check_err!(db.has_users().await));
+        if let Some(token) = setup.token().await.as_ref() {
+            let special_route = check_err!(conf.get(SpecialRoute));
+            println!(
+                "No users found, create the first admin at {}/setup",
+                special_route
+            );
+            println!("Setup token: {}", token);
+        }
+
+        // Older versions created these accounts on their own, anyone can log in with them
+        for username in check_err!(db.seeded_accounts().await) {
+            eprintln!(
+                "Warning: the account {} still has the default password it was created with, change its password or delete it",
+                username
+            );
+        }
 
         // Initialize the template engine
         let static_path = check_err!(conf.get(StaticDir));
@@ -27,11 +57,22 @@ impl AppState {
         // Create the client
         let client = hyper::Client::builder().build(HttpsConnector::new());
 
+        // Check the health of the upstreams in the background
+        let health = Health::new(conf);
+        health.spawn_prober(db.clone(), client.clone());
+
         // Clone the config
         let conf = conf.clone();
 
         Self {
             sessions,
+            setup,
+            lockouts: Lockouts::new(),
+            pending_logins: PendingLogins::new(),
+            passkey_challenges: PasskeyChallenges::new(),
+            totp_enrollments: TotpEnrollments::new(),
+            balancer: Balancer::new(),
+            health,
             client,
             conf,
             tera,
@@ -48,4 +89,32 @@ impl AppState {
             self.db.clone(),
         )
     }
+
+    pub fn setup(&self) -> SetupToken {
+        self.setup.clone()
+    }
+
+    pub fn lockouts(&self) -> Lockouts {
+        self.lockouts.clone()
+    }
+
+    pub fn pending_logins(&self) -> PendingLogins {
+        self.pending_logins.clone()
+    }
+
+    pub fn passkey_challenges(&self) -> PasskeyChallenges {
+        self.passkey_challenges.clone()
+    }
+
+    pub fn totp_enrollments(&self) -> TotpEnrollments {
+        self.totp_enrollments.clone()
+    }
+
+    pub fn balancer(&self) -> Balancer {
+        self.balancer.clone()
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
 }
@@ -0,0 +1,66 @@
+use crate::*;
+
+use chrono::{DateTime, Duration, Utc};
+use hashbrown::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// How long the browser has to answer a passkey challenge (5 minutes)
+const CHALLENGE_TIME: i64 = 5 * 60;
+
+// What a passkey challenge was given out for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChallengePurpose {
+    // Adding a passkey to the account of this user
+    Register(String),
+    // Logging in with any passkey
+    Login,
+}
+
+#[derive(Clone, Debug)]
+struct Challenge {
+    purpose: ChallengePurpose,
+    expire_time: DateTime<Utc>,
+}
+
+// Passkey challenges are only kept in memory, they are short-lived anyway
+#[derive(Clone, Default)]
+pub struct PasskeyChallenges {
+    store: Arc<Mutex<HashMap<String, Challenge>>>,
+}
+
+impl PasskeyChallenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Give out a new challenge
+    pub async fn issue(&self, purpose: ChallengePurpose) -> String {
+        let challenge = webauthn::generate_challenge();
+        let now = Utc::now();
+        let mut store = self.store.lock().await;
+
+        // Forget the challenges that were never answered
+        store.retain(|_, challenge| challenge.expire_time > now);
+
+        store.insert(
+            challenge.clone(),
+            Challenge {
+                purpose,
+                expire_time: now + Duration::seconds(CHALLENGE_TIME),
+            },
+        );
+
+        challenge
+    }
+
+    // Use up a challenge, so every challenge can only be answered once
+    pub async fn take(&self, challenge: &str) -> Option<ChallengePurpose> {
+        self.store
+            .lock()
+            .await
+            .remove(challenge)
+            .filter(|challenge| challenge.expire_time > Utc::now())
+            .map(|challenge| challenge.purpose)
+    }
+}
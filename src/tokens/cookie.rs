@@ -0,0 +1,79 @@
+use super::*;
+use crate::*;
+
+use axum_extra::extract::cookie::{Cookie, SameSite};
+
+// Build a cookie with the attributes from the config
+pub(super) fn base_cookie(conf: &Config, name: String, value: String) -> Cookie<'static> {
+    let domain = check_err!(conf.get(CookieDomain));
+    let secure = check_err!(check_err!(conf.get(CookieSecure)).parse::<bool>());
+    let same_site = match check_err!(conf.get(CookieSameSite)).to_lowercase().as_str() {
+        "strict" => SameSite::Strict,
+        "lax" => SameSite::Lax,
+        "none" => SameSite::None,
+        other => check_err!(Err::<SameSite, _>(format!(
+            "Invalid value for {}: {} (expected strict, lax or none)",
+            CookieSameSite, other
+        ))),
+    };
+
+    let mut cookie = Cookie::new(name, value);
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_secure(secure);
+    cookie.set_same_site(same_site);
+    if !domain.is_empty() {
+        cookie.set_domain(domain);
+    }
+
+    cookie
+}
+
+// Build the session cookie, it lives as long as the session
+pub fn session_cookie(conf: &Config, token: String, session: &Session) -> Cookie<'static> {
+    let cookie_name = check_err!(conf.get(CookieName));
+    let mut cookie = base_cookie(conf, cookie_name, token);
+    cookie.set_max_age(time::Duration::seconds(session.max_age()));
+
+    cookie
+}
+
+// Build the cookie that removes the session cookie from the browser, it needs the same
+// domain and path as the session cookie or the browser keeps the old one
+pub fn removal_cookie(conf: &Config) -> Cookie<'static> {
+    let cookie_name = check_err!(conf.get(CookieName));
+    let mut cookie = base_cookie(conf, cookie_name, String::new());
+    cookie.make_removal();
+
+    cookie
+}
+
+// Name of the cookie that remembers a login waiting for its second factor
+pub fn pending_login_cookie_name(conf: &Config) -> String {
+    format!("{}-login", check_err!(conf.get(CookieName)))
+}
+
+// Build the cookie for a login waiting for its second factor
+pub fn pending_login_cookie(conf: &Config, token: String) -> Cookie<'static> {
+    let mut cookie = base_cookie(conf, pending_login_cookie_name(conf), token);
+    cookie.set_max_age(time::Duration::seconds(pending::PENDING_LOGIN_TIME));
+
+    cookie
+}
+
+// Build the cookie that removes the pending login cookie from the browser
+pub fn pending_login_removal_cookie(conf: &Config) -> Cookie<'static> {
+    let mut cookie = base_cookie(conf, pending_login_cookie_name(conf), String::new());
+    cookie.make_removal();
+
+    cookie
+}
+
+// Names of all cookies proxrs sets itself, upstreams never get to see them
+pub fn proxrs_cookie_names(conf: &Config) -> [String; 3] {
+    [
+        check_err!(conf.get(CookieName)),
+        csrf::csrf_cookie_name(conf),
+        pending_login_cookie_name(conf),
+    ]
+}
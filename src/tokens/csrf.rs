@@ -0,0 +1,91 @@
+use super::cookie::base_cookie;
+use crate::*;
+
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Name of the form field holding the csrf token
+const CSRF_FIELD: &str = "csrf_token";
+
+// Name of the cookie holding the csrf token
+pub(super) fn csrf_cookie_name(conf: &Config) -> String {
+    format!("{}-csrf", check_err!(conf.get(CookieName)))
+}
+
+// Get the csrf token for a form, with a cookie to set when the browser doesn't have one yet
+// The token is kept in a cookie and in the form (double submit), a cross-site form can't read the cookie to copy it
+pub fn csrf_token(conf: &Config, jar: &CookieJar) -> (String, Option<Cookie<'static>>) {
+    if let Some(cookie) = jar.get(&csrf_cookie_name(conf)) {
+        if !cookie.value().is_empty() {
+            return (cookie.value().to_owned(), None);
+        }
+    }
+
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let cookie = base_cookie(conf, csrf_cookie_name(conf), token.clone());
+
+    (token, Some(cookie))
+}
+
+// Check the csrf token of a submitted form against the cookie
+pub fn csrf_valid(conf: &Config, jar: &CookieJar, body: &[u8]) -> bool {
+    let fields = serde_urlencoded::from_bytes::<HashMap<String, String>>(body).unwrap_or_default();
+
+    match fields.get(CSRF_FIELD) {
+        Some(token) => csrf_matches(conf, jar, token),
+        None => false,
+    }
+}
+
+// Check a csrf token sent some other way (like in a json body) against the cookie
+pub fn csrf_matches(conf: &Config, jar: &CookieJar, token: &str) -> bool {
+    match jar.get(&csrf_cookie_name(conf)) {
+        Some(cookie) if !cookie.value().is_empty() => {
+            password::constant_time_eq(token.as_bytes(), cookie.value().as_bytes())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jar(conf: &Config, token: &str) -> CookieJar {
+        CookieJar::new().add(Cookie::new(csrf_cookie_name(conf), token.to_owned()))
+    }
+
+    #[test]
+    fn checks_the_form_token_against_the_cookie() {
+        let conf = Config::for_tests(&[(CookieName, "proxrs")]);
+        let (token, cookie) = csrf_token(&conf, &CookieJar::new());
+        let cookie = cookie.unwrap();
+        assert_eq!(cookie.value(), token);
+        let jar = CookieJar::new().add(cookie);
+        let body = format!("username=stan&{}={}", CSRF_FIELD, token);
+
+        // The happy path, the browser keeps its token
+        assert!(csrf_valid(&conf, &jar, body.as_bytes()));
+        assert_eq!(csrf_token(&conf, &jar), (token.clone(), None));
+
+        let cases: [(&str, CookieJar, String); 5] = [
+            ("missing cookie", CookieJar::new(), body.clone()),
+            (
+                "empty cookie",
+                self::jar(&conf, ""),
+                format!("{}=", CSRF_FIELD),
+            ),
+            ("missing field", jar.clone(), "username=stan".to_string()),
+            (
+                "mismatch",
+                jar.clone(),
+                format!("{}=x{}", CSRF_FIELD, &token[1..]),
+            ),
+            ("broken body", jar.clone(), "%%%".to_string()),
+        ];
+        for (case, jar, body) in cases {
+            assert!(!csrf_valid(&conf, &jar, body.as_bytes()), "{}", case);
+        }
+    }
+}
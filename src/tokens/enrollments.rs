@@ -0,0 +1,60 @@
+use crate::*;
+
+use chrono::{DateTime, Duration, Utc};
+use hashbrown::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// How long a new totp secret on the account page can wait for its first code (15 minutes)
+const ENROLL_TIME: i64 = 15 * 60;
+
+#[derive(Clone, Debug)]
+struct Enrollment {
+    secret: String,
+    expire_time: DateTime<Utc>,
+}
+
+// Totp secrets that are being set up on the account page, kept here so the browser can't choose its own
+#[derive(Clone, Default)]
+pub struct TotpEnrollments {
+    store: Arc<Mutex<HashMap<String, Enrollment>>>,
+}
+
+impl TotpEnrollments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Get the secret the user is setting up, starting with a new one when there is none
+    pub async fn start(&self, user: &str) -> String {
+        let now = Utc::now();
+        let mut store = self.store.lock().await;
+
+        // Forget the secrets that were never confirmed
+        store.retain(|_, enrollment| enrollment.expire_time > now);
+
+        store
+            .entry(user.to_owned())
+            .or_insert_with(|| Enrollment {
+                secret: totp::generate_secret(),
+                expire_time: now + Duration::seconds(ENROLL_TIME),
+            })
+            .secret
+            .clone()
+    }
+
+    // Get the secret the user is setting up, if it hasn't expired
+    pub async fn get(&self, user: &str) -> Option<String> {
+        self.store
+            .lock()
+            .await
+            .get(user)
+            .filter(|enrollment| enrollment.expire_time > Utc::now())
+            .map(|enrollment| enrollment.secret.clone())
+    }
+
+    // Forget the secret once it is saved
+    pub async fn finish(&self, user: &str) {
+        self.store.lock().await.remove(user);
+    }
+}
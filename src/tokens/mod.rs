@@ -1,5 +1,20 @@
+pub use challenges::{ChallengePurpose, PasskeyChallenges};
+pub use cookie::{
+    pending_login_cookie, pending_login_cookie_name, pending_login_removal_cookie,
+    proxrs_cookie_names, removal_cookie, session_cookie,
+};
+pub use csrf::{csrf_matches, csrf_token, csrf_valid};
+pub use enrollments::TotpEnrollments;
+pub use pending::{PendingLogin, PendingLogins};
 pub use session::Session;
 pub use sessions::Sessions;
+pub use setup::SetupToken;
 
+mod challenges;
+mod cookie;
+mod csrf;
+mod enrollments;
+mod pending;
 mod session;
 mod sessions;
+mod setup;
@@ -0,0 +1,96 @@
+use chrono::{DateTime, Duration, Utc};
+use hashbrown::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+// How long the second login step can take (5 minutes)
+pub const PENDING_LOGIN_TIME: i64 = 5 * 60;
+
+// How many wrong codes are allowed before the password has to be entered again
+const MAX_ATTEMPTS: u32 = 5;
+
+// A login that passed the password check but still needs a second factor
+#[derive(Clone, Debug)]
+pub struct PendingLogin {
+    pub user: String,
+    pub next: Option<String>,
+    // The new totp secret when the user has to set up two-factor authentication first
+    pub enroll_secret: Option<String>,
+    expire_time: DateTime<Utc>,
+    attempts: u32,
+}
+
+// Pending logins are only kept in memory, they are short-lived anyway
+#[derive(Clone, Default)]
+pub struct PendingLogins {
+    store: Arc<Mutex<HashMap<String, PendingLogin>>>,
+}
+
+impl PendingLogins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Start the second login step, returns the token for the cookie
+    pub async fn start(
+        &self,
+        user: String,
+        next: Option<String>,
+        enroll_secret: Option<String>,
+    ) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        let now = Utc::now();
+        let mut store = self.store.lock().await;
+
+        // Forget the logins that were never finished
+        store.retain(|_, pending| pending.expire_time > now);
+
+        store.insert(
+            token.clone(),
+            PendingLogin {
+                user,
+                next,
+                enroll_secret,
+                expire_time: now + Duration::seconds(PENDING_LOGIN_TIME),
+                attempts: 0,
+            },
+        );
+
+        token
+    }
+
+    // Get a pending login that hasn't expired
+    pub async fn get(&self, token: &str) -> Option<PendingLogin> {
+        self.store
+            .lock()
+            .await
+            .get(token)
+            .filter(|pending| pending.expire_time > Utc::now())
+            .cloned()
+    }
+
+    // Count a wrong code, returns false when the login has to start over
+    pub async fn failed(&self, token: &str) -> bool {
+        let mut store = self.store.lock().await;
+        let attempts = match store.get_mut(token) {
+            Some(pending) => {
+                pending.attempts += 1;
+                pending.attempts
+            }
+            None => return false,
+        };
+
+        if attempts >= MAX_ATTEMPTS {
+            store.remove(token);
+            return false;
+        }
+
+        true
+    }
+
+    // End a pending login
+    pub async fn finish(&self, token: &str) {
+        self.store.lock().await.remove(token);
+    }
+}
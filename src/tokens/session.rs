@@ -1,38 +1,83 @@
 use crate::*;
 
 use chrono::{DateTime, Duration, Utc};
+use sha2::Digest;
 
+// A session expires when it hasn't been used for the idle timeout, and always once it reaches its maximum lifetime
+// It is renewed (and written back to the store) once half of the idle timeout has passed since the last renewal
 #[derive(Clone, Debug)]
 pub struct Session {
     pub admin: bool,
     pub user: String,
-    pub token: String,
-    renew_time: DateTime<Utc>,
-    expire_time: DateTime<Utc>,
+    pub groups: Vec<String>,
+    pub id: String,
+    pub(crate) created_time: DateTime<Utc>,
+    pub(crate) renew_time: DateTime<Utc>,
+    pub(crate) expire_time: DateTime<Utc>,
 }
 
 impl Session {
-    pub async fn new(user: String, token: String, expire_time: i64, db: &Db) -> Self {
-        let renew_time = Utc::now() + chrono::Duration::from(Duration::seconds(expire_time / 2));
-        let expire_time = Utc::now() + chrono::Duration::from(Duration::seconds(expire_time));
-
-        // Check if the user is an admin
+    pub async fn new(
+        user: String,
+        token: &str,
+        idle_timeout: i64,
+        max_lifetime: i64,
+        db: &Db,
+    ) -> Self {
+        // Check if the user is an admin and get their groups
         let admin = db.is_admin(&user).await.unwrap_or(false);
+        let groups = db.get_groups(&user).await.unwrap_or_default();
 
-        Self {
+        let now = Utc::now();
+        let mut session = Self {
             user,
             admin,
-            token,
-            renew_time,
-            expire_time,
-        }
+            groups,
+            id: Session::id_of(token),
+            created_time: now,
+            renew_time: now,
+            expire_time: now,
+        };
+        session.renew(idle_timeout, max_lifetime);
+
+        session
+    }
+
+    // Id of the session belonging to a token, so sessions can be stored and listed without leaking their tokens
+    pub fn id_of(token: &str) -> String {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(token);
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn expire_time(&self) -> DateTime<Utc> {
+        self.expire_time
     }
 
     pub fn expired(&self) -> bool {
         Utc::now() > self.expire_time
     }
 
-    pub fn renew(&mut self) {
-        self.expire_time = Utc::now() + self.expire_time.signed_duration_since(self.renew_time);
+    // Seconds until the session expires, used as the Max-Age of the cookie
+    pub fn max_age(&self) -> i64 {
+        let millis = (self.expire_time - Utc::now()).num_milliseconds().max(0);
+        (millis + 999) / 1000
+    }
+
+    // Check if it is time to renew the session
+    pub fn needs_renewal(&self, max_lifetime: i64) -> bool {
+        Utc::now() >= self.renew_time && self.expire_time < self.max_expire_time(max_lifetime)
+    }
+
+    // Slide the expire time forward, without going past the maximum lifetime
+    pub fn renew(&mut self, idle_timeout: i64, max_lifetime: i64) {
+        let now = Utc::now();
+        self.expire_time =
+            (now + Duration::seconds(idle_timeout)).min(self.max_expire_time(max_lifetime));
+        self.renew_time = now + Duration::seconds(idle_timeout / 2);
+    }
+
+    fn max_expire_time(&self, max_lifetime: i64) -> DateTime<Utc> {
+        self.created_time + Duration::seconds(max_lifetime)
     }
 }
@@ -1,62 +1,158 @@
 use super::*;
 use crate::*;
 
+use axum_extra::extract::CookieJar;
 use hashbrown::HashMap;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-#[derive(Clone, Debug)]
+// Sessions are kept in memory and written through to the database, so they survive a restart
+#[derive(Clone)]
 pub struct Sessions {
     store: Arc<Mutex<HashMap<String, Session>>>,
+    db: Db,
 }
 
 impl Sessions {
-    pub fn new() -> Self {
-        Self {
-            store: Arc::new(Mutex::new(HashMap::new())),
-        }
+    // Create the session store and load the sessions that are still valid from the database
+    pub async fn new(db: Db) -> Result<Self, Error> {
+        let store = db
+            .get_sessions()
+            .await?
+            .into_iter()
+            .map(|session| (session.id.clone(), session))
+            .collect();
+
+        Ok(Self {
+            store: Arc::new(Mutex::new(store)),
+            db,
+        })
     }
 
-    pub async fn new_session(&mut self, user: String, conf: &Config, db: &Db) -> Session {
-        // Get expire time from config
-        let expire_time = check_err!(conf.get(SessionExpireTime))
-            .parse::<i64>()
-            .unwrap();
+    // Create a new session, returns the session and the token for the cookie
+    pub async fn new_session(
+        &mut self,
+        user: String,
+        conf: &Config,
+    ) -> Result<(Session, String), Error> {
+        // Get the timeouts from the config
+        let (idle_timeout, max_lifetime) = timeouts(conf);
 
         // Create a new session
         let token = Uuid::new_v4().to_string();
-        let session = Session::new(user, token.clone(), expire_time, db).await;
-        self.store().await.insert(token, session.clone());
+        let session = Session::new(user, &token, idle_timeout, max_lifetime, &self.db).await;
+        self.db.insert_session(&session).await?;
+        self.store()
+            .await
+            .insert(session.id.clone(), session.clone());
 
         // Return the session
-        session
+        Ok((session, token))
     }
 
     // Get the session from the store
-    // TODO: Make this return an useful error
     pub async fn get(&self, token: &str) -> Option<Session> {
         // Get the sessions from the store
         let sessions = self.store().await;
 
         // Get the session
-        match sessions.get(token) {
-            Some(session) => Some(session.clone()),
-            None => None,
+        sessions.get(&Session::id_of(token)).cloned()
+    }
+
+    // Get the session belonging to the session cookie, if it is still valid
+    pub async fn get_from_cookie(&self, jar: &CookieJar, conf: &Config) -> Option<Session> {
+        let cookie_name = check_err!(conf.get(CookieName));
+        let session = self.get(jar.get(&cookie_name)?.value()).await?;
+
+        match session.expired() {
+            true => None,
+            false => Some(session),
         }
     }
 
+    // Get all sessions that are still valid
+    pub async fn all(&self) -> Vec<Session> {
+        self.store()
+            .await
+            .values()
+            .filter(|session| !session.expired())
+            .cloned()
+            .collect()
+    }
+
+    // Renew the session when it is due, returns if it was renewed
+    pub async fn renew(&self, session: &mut Session, conf: &Config) -> Result<bool, Error> {
+        // Get the timeouts from the config
+        let (idle_timeout, max_lifetime) = timeouts(conf);
+
+        // Check if the session has to be renewed
+        if !session.needs_renewal(max_lifetime) {
+            return Ok(false);
+        }
+
+        // Renew the session and write it back to the store
+        session.renew(idle_timeout, max_lifetime);
+        self.db.update_session(session).await?;
+        if let Some(stored) = self.store().await.get_mut(&session.id) {
+            *stored = session.clone();
+        }
+
+        Ok(true)
+    }
+
+    // Remove a session by its id, returns if it existed
+    pub async fn delete_by_id(&mut self, id: &str) -> Result<bool, Error> {
+        self.db.delete_session(id).await?;
+
+        Ok(self.store().await.remove(id).is_some())
+    }
+
+    // Remove all sessions of a user from the store
+    pub async fn delete_user(&mut self, user: &str) -> Result<(), Error> {
+        self.db.delete_user_sessions(user).await?;
+        self.store().await.retain(|_, session| session.user != user);
+
+        Ok(())
+    }
+
     // Remove the session from the store
-    // TODO: Make this return an useful error
-    pub async fn delete(&mut self, session: Session) -> Result<(), ()> {
-        // Get the sessions from the store
+    pub async fn delete(&mut self, session: Session) -> Result<(), Error> {
+        match self.delete_by_id(&session.id).await? {
+            true => Ok(()),
+            false => Err(Error::NotFound("Session".to_string())),
+        }
+    }
+
+    // Remove all expired sessions, returns how many were removed
+    pub async fn reap(&mut self) -> Result<usize, Error> {
+        // Remove them from the database
+        let deleted = self.db.delete_expired_sessions().await?;
+
+        // Remove them from the store
         let mut sessions = self.store().await;
+        let before = sessions.len();
+        sessions.retain(|_, session| !session.expired());
 
-        // Remove the session
-        match sessions.remove(&session.token) {
-            Some(_) => Ok(()),
-            None => Err(()),
-        }
+        Ok(deleted.max(before - sessions.len()))
+    }
+
+    // Periodically remove expired sessions in the background
+    pub fn spawn_reaper(&self, interval: Duration) {
+        let mut sessions = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+
+                match sessions.reap().await {
+                    Ok(0) => (),
+                    Ok(count) => println!("Reaped {} expired sessions", count),
+                    Err(err) => eprintln!("Failed to reap expired sessions: {}", err),
+                }
+            }
+        });
     }
 
     // Get the sessions from the store
@@ -64,3 +160,11 @@ impl Sessions {
         self.store.lock().await
     }
 }
+
+// Get the idle timeout and maximum lifetime of sessions from the config
+fn timeouts(conf: &Config) -> (i64, i64) {
+    let idle_timeout = check_err!(check_err!(conf.get(SessionExpireTime)).parse::<i64>());
+    let max_lifetime = check_err!(check_err!(conf.get(SessionMaxLifetime)).parse::<i64>());
+
+    (idle_timeout, max_lifetime.max(idle_timeout))
+}
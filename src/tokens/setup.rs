@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+// One-time token that guards the first-run setup page
+#[derive(Clone, Debug)]
+pub struct SetupToken {
+    token: Arc<Mutex<Option<String>>>,
+}
+
+impl SetupToken {
+    // Create a setup token, only when there are no users yet
+    pub fn new(has_users: bool) -> Self {
+        let token = match has_users {
+            true => None,
+            false => Some(Uuid::new_v4().simple().to_string()),
+        };
+
+        Self {
+            token: Arc::new(Mutex::new(token)),
+        }
+    }
+
+    // Check if the setup still has to be done
+    pub async fn pending(&self) -> bool {
+        self.token().await.is_some()
+    }
+
+    // Lock the token, hold the guard while creating the first user so the setup can only happen once
+    pub async fn token(&self) -> MutexGuard<'_, Option<String>> {
+        self.token.lock().await
+    }
+}
@@ -0,0 +1,583 @@
+use crate::*;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+// Only ES256 (ECDSA with P-256 and SHA-256) keys are supported, every passkey provider offers it
+const ES256: i128 = -7;
+
+// Authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// How long the browser may take to talk to the authenticator (in milliseconds)
+const TIMEOUT: u64 = 5 * 60 * 1000;
+
+// The site passkeys are made for
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+// A passkey that was just registered
+pub struct NewCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+// The parts of the authenticator data that are checked
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    rest: &'a [u8],
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>, &'static str> {
+    URL_SAFE_NO_PAD
+        .decode(data)
+        .map_err(|_| "The passkey response is not valid base64url")
+}
+
+// Generate a random challenge for the authenticator to sign
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    OsRng.fill_bytes(&mut challenge);
+
+    encode(&challenge)
+}
+
+// Get the challenge a response was made for, to look up what it belongs to
+pub fn challenge_of(client_data_json: &[u8]) -> Result<String, &'static str> {
+    Ok(parse_client_data(client_data_json)?.challenge)
+}
+
+// Options for navigator.credentials.create(), binary values are base64url encoded
+pub fn creation_options(
+    rp: &RelyingParty,
+    user_handle: &[u8],
+    username: &str,
+    challenge: &str,
+    exclude_credentials: &[String],
+) -> serde_json::Value {
+    json!({
+        "challenge": challenge,
+        "rp": { "id": rp.id, "name": rp.name },
+        "user": { "id": encode(user_handle), "name": username, "displayName": username },
+        "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 as i64 }],
+        "timeout": TIMEOUT,
+        "attestation": "none",
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "requireResidentKey": true,
+            "userVerification": "required",
+        },
+        "excludeCredentials": exclude_credentials
+            .iter()
+            .map(|id| json!({ "type": "public-key", "id": id }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+// Options for navigator.credentials.get(), without credentials so the user can pick any passkey for the site
+pub fn request_options(rp: &RelyingParty, challenge: &str) -> serde_json::Value {
+    json!({
+        "challenge": challenge,
+        "rpId": rp.id,
+        "timeout": TIMEOUT,
+        "userVerification": "required",
+    })
+}
+
+// Check the response of navigator.credentials.create() and get the new credential from it
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &str,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<NewCredential, &'static str> {
+    check_client_data(rp, "webauthn.create", challenge, client_data_json)?;
+
+    // Only the authenticator data is used, attestation statements aren't checked ("none" was asked for)
+    let attestation: Value = ciborium::de::from_reader(attestation_object)
+        .map_err(|_| "The attestation object is not valid CBOR")?;
+    let auth_data = map_get(&attestation, |key| key.as_text() == Some("authData"))
+        .and_then(Value::as_bytes)
+        .ok_or("The attestation object has no authenticator data")?;
+    let auth_data = parse_authenticator_data(rp, auth_data)?;
+    if auth_data.flags & ATTESTED_CREDENTIAL == 0 {
+        return Err("The authenticator did not return a credential");
+    }
+
+    // Attested credential data: aaguid (16), credential id length (2), credential id, cose key
+    let rest = auth_data.rest;
+    if rest.len() < 18 {
+        return Err("The credential data is too short");
+    }
+    let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+    let credential_id = rest
+        .get(18..18 + id_len)
+        .ok_or("The credential data is too short")?;
+    let cose_key: Value = ciborium::de::from_reader(&rest[18 + id_len..])
+        .map_err(|_| "The credential public key is not valid CBOR")?;
+
+    Ok(NewCredential {
+        credential_id: encode(credential_id),
+        public_key: cose_to_sec1(&cose_key)?,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+// Check the response of navigator.credentials.get() against a stored credential, returns the new signature counter
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &str,
+    public_key: &[u8],
+    sign_count: u32,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+) -> Result<u32, &'static str> {
+    check_client_data(rp, "webauthn.get", challenge, client_data_json)?;
+    let auth_data = parse_authenticator_data(rp, authenticator_data)?;
+
+    // A counter that doesn't go up means the credential may have been cloned (passkeys that sync always send 0)
+    if (auth_data.sign_count != 0 || sign_count != 0) && auth_data.sign_count <= sign_count {
+        return Err("The signature counter of the passkey went backwards");
+    }
+
+    // The signature covers the authenticator data and the hash of the client data
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data_json));
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| "The stored key is invalid")?;
+    let signature = Signature::from_der(signature).map_err(|_| "The signature is malformed")?;
+    key.verify(&message, &signature)
+        .map_err(|_| "The signature is not valid")?;
+
+    Ok(auth_data.sign_count)
+}
+
+fn parse_client_data(client_data_json: &[u8]) -> Result<ClientData, &'static str> {
+    serde_json::from_slice(client_data_json).map_err(|_| "The client data is not valid json")
+}
+
+// Check the type, challenge and origin the browser signed
+fn check_client_data(
+    rp: &RelyingParty,
+    kind: &str,
+    challenge: &str,
+    client_data_json: &[u8],
+) -> Result<(), &'static str> {
+    let client_data = parse_client_data(client_data_json)?;
+    if client_data.kind != kind {
+        return Err("The client data is for another ceremony");
+    }
+    if !password::constant_time_eq(client_data.challenge.as_bytes(), challenge.as_bytes()) {
+        return Err("The challenge doesn't match");
+    }
+    if !origin_allowed(rp, &client_data.origin) {
+        return Err("The origin is not part of this site");
+    }
+
+    Ok(())
+}
+
+// Origins have to be the relying party or a subdomain of it, over https unless it is localhost
+fn origin_allowed(rp: &RelyingParty, origin: &str) -> bool {
+    let (scheme, host) = match origin.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+
+    let same_site = host == rp.id || host.ends_with(&format!(".{}", rp.id));
+    let secure = scheme == "https" || (scheme == "http" && host == "localhost");
+    same_site && secure
+}
+
+// Authenticator data: rp id hash (32), flags (1), signature counter (4), then the optional parts
+fn parse_authenticator_data<'a>(
+    rp: &RelyingParty,
+    data: &'a [u8],
+) -> Result<AuthenticatorData<'a>, &'static str> {
+    if data.len() < 37 {
+        return Err("The authenticator data is too short");
+    }
+    let auth_data = AuthenticatorData {
+        rp_id_hash: &data[..32],
+        flags: data[32],
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[37..],
+    };
+
+    if *auth_data.rp_id_hash != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err("The passkey is for another site");
+    }
+    if auth_data.flags & USER_PRESENT == 0 || auth_data.flags & USER_VERIFIED == 0 {
+        return Err("The user was not verified by the authenticator");
+    }
+
+    Ok(auth_data)
+}
+
+// Turn an EC2 P-256 COSE key into an uncompressed SEC1 point
+fn cose_to_sec1(key: &Value) -> Result<Vec<u8>, &'static str> {
+    let int_param = |label: i128| {
+        map_get(key, |k| k.as_integer().map(i128::from) == Some(label))
+            .and_then(Value::as_integer)
+            .map(i128::from)
+    };
+    let bytes_param = |label: i128| {
+        map_get(key, |k| k.as_integer().map(i128::from) == Some(label)).and_then(Value::as_bytes)
+    };
+
+    // kty 2 (EC2), alg -7 (ES256), crv 1 (P-256)
+    if int_param(1) != Some(2) || int_param(3) != Some(ES256) || int_param(-1) != Some(1) {
+        return Err("Only ES256 passkeys are supported");
+    }
+    let (x, y) = match (bytes_param(-2), bytes_param(-3)) {
+        (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => (x, y),
+        _ => return Err("The credential public key is malformed"),
+    };
+
+    let mut point = vec![0x04];
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+
+    // Make sure the point is on the curve before storing it
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| "The credential public key is invalid")?;
+
+    Ok(point)
+}
+
+fn map_get(map: &Value, matches: impl Fn(&Value) -> bool) -> Option<&Value> {
+    map.as_map()?
+        .iter()
+        .find(|(key, _)| matches(key))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    const CHALLENGE: &str = "c2lnbi10aGlzLWNoYWxsZW5nZQ";
+    const ORIGIN: &str = "https://auth.example.com";
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "example.com".to_string(),
+            name: "Example".to_string(),
+        }
+    }
+
+    // A software authenticator with a fixed key, so no hardware is needed
+    struct Authenticator {
+        key: SigningKey,
+        credential_id: Vec<u8>,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::from_bytes(&[0x42; 32].into()).unwrap(),
+                credential_id: vec![0x07; 16],
+            }
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.key
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec()
+        }
+
+        fn cose_key(&self) -> Value {
+            let point = self.public_key();
+            Value::Map(vec![
+                (Value::Integer(1.into()), Value::Integer(2.into())),
+                (Value::Integer(3.into()), Value::Integer((-7).into())),
+                (Value::Integer((-1).into()), Value::Integer(1.into())),
+                (
+                    Value::Integer((-2).into()),
+                    Value::Bytes(point[1..33].to_vec()),
+                ),
+                (
+                    Value::Integer((-3).into()),
+                    Value::Bytes(point[33..].to_vec()),
+                ),
+            ])
+        }
+
+        fn auth_data(&self, rp_id: &str, flags: u8, sign_count: u32, rest: &[u8]) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&sign_count.to_be_bytes());
+            data.extend_from_slice(rest);
+            data
+        }
+
+        // Authenticator data with the attested credential, wrapped in a "none" attestation object
+        fn attestation_object(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+            let mut credential = vec![0; 16];
+            credential.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            credential.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&self.cose_key(), &mut credential).unwrap();
+
+            let auth_data = self.auth_data(rp_id, flags | ATTESTED_CREDENTIAL, 0, &credential);
+            let attestation = Value::Map(vec![
+                (Value::Text("fmt".into()), Value::Text("none".into())),
+                (Value::Text("attStmt".into()), Value::Map(Vec::new())),
+                (Value::Text("authData".into()), Value::Bytes(auth_data)),
+            ]);
+            let mut object = Vec::new();
+            ciborium::ser::into_writer(&attestation, &mut object).unwrap();
+            object
+        }
+
+        fn sign(&self, auth_data: &[u8], client_data_json: &[u8]) -> Vec<u8> {
+            let mut message = auth_data.to_vec();
+            message.extend_from_slice(&Sha256::digest(client_data_json));
+            let signature: Signature = self.key.sign(&message);
+            signature.to_der().as_bytes().to_vec()
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        json!({ "type": kind, "challenge": challenge, "origin": origin })
+            .to_string()
+            .into_bytes()
+    }
+
+    const FLAGS: u8 = USER_PRESENT | USER_VERIFIED;
+
+    const CHALLENGE_ERR: &str = "The challenge doesn't match";
+    const ORIGIN_ERR: &str = "The origin is not part of this site";
+    const TYPE_ERR: &str = "The client data is for another ceremony";
+
+    // Another rp id, and a missing user present or user verified flag
+    const AUTH_DATA_CASES: [(&str, u8, &str); 3] = [
+        ("example.org", FLAGS, "The passkey is for another site"),
+        (
+            "example.com",
+            USER_VERIFIED,
+            "The user was not verified by the authenticator",
+        ),
+        (
+            "example.com",
+            USER_PRESENT,
+            "The user was not verified by the authenticator",
+        ),
+    ];
+
+    fn register(
+        client_data_json: &[u8],
+        attestation_object: &[u8],
+    ) -> Result<NewCredential, &'static str> {
+        verify_registration(&rp(), CHALLENGE, client_data_json, attestation_object)
+    }
+
+    // Sign an assertion with the given parts and check it against the stored counter
+    fn assert(
+        authenticator: &Authenticator,
+        client_data_json: &[u8],
+        auth_data: &[u8],
+        stored_count: u32,
+    ) -> Result<u32, &'static str> {
+        let signature = authenticator.sign(auth_data, client_data_json);
+        verify_assertion(
+            &rp(),
+            CHALLENGE,
+            &authenticator.public_key(),
+            stored_count,
+            client_data_json,
+            auth_data,
+            &signature,
+        )
+    }
+
+    #[test]
+    fn registration_succeeds() {
+        let authenticator = Authenticator::new();
+        let credential = register(
+            &client_data("webauthn.create", CHALLENGE, ORIGIN),
+            &authenticator.attestation_object("example.com", FLAGS),
+        )
+        .unwrap();
+
+        assert_eq!(
+            credential.credential_id,
+            encode(&authenticator.credential_id)
+        );
+        assert_eq!(credential.public_key, authenticator.public_key());
+        assert_eq!(credential.sign_count, 0);
+    }
+
+    #[test]
+    fn registration_checks_client_data() {
+        let object = Authenticator::new().attestation_object("example.com", FLAGS);
+        let cases = [
+            (
+                "webauthn.create",
+                "b3RoZXItY2hhbGxlbmdl",
+                ORIGIN,
+                CHALLENGE_ERR,
+            ),
+            (
+                "webauthn.create",
+                CHALLENGE,
+                "https://example.org",
+                ORIGIN_ERR,
+            ),
+            (
+                "webauthn.create",
+                CHALLENGE,
+                "http://auth.example.com",
+                ORIGIN_ERR,
+            ),
+            ("webauthn.get", CHALLENGE, ORIGIN, TYPE_ERR),
+        ];
+        for (kind, challenge, origin, err) in cases {
+            let result = register(&client_data(kind, challenge, origin), &object);
+            assert_eq!(result.err(), Some(err));
+        }
+    }
+
+    #[test]
+    fn registration_checks_authenticator_data() {
+        let authenticator = Authenticator::new();
+        let client_data_json = client_data("webauthn.create", CHALLENGE, ORIGIN);
+        for (rp_id, flags, err) in AUTH_DATA_CASES {
+            let object = authenticator.attestation_object(rp_id, flags);
+            assert_eq!(register(&client_data_json, &object).err(), Some(err));
+        }
+    }
+
+    #[test]
+    fn assertion_succeeds() {
+        let authenticator = Authenticator::new();
+        let client_data_json = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        let auth_data = authenticator.auth_data("example.com", FLAGS, 5, &[]);
+
+        assert_eq!(
+            assert(&authenticator, &client_data_json, &auth_data, 4),
+            Ok(5)
+        );
+    }
+
+    #[test]
+    fn assertion_checks_client_data() {
+        let authenticator = Authenticator::new();
+        let auth_data = authenticator.auth_data("example.com", FLAGS, 5, &[]);
+        let cases = [
+            (
+                "webauthn.get",
+                "b3RoZXItY2hhbGxlbmdl",
+                ORIGIN,
+                CHALLENGE_ERR,
+            ),
+            ("webauthn.get", CHALLENGE, "https://example.org", ORIGIN_ERR),
+            ("webauthn.create", CHALLENGE, ORIGIN, TYPE_ERR),
+        ];
+        for (kind, challenge, origin, err) in cases {
+            let client_data_json = client_data(kind, challenge, origin);
+            let result = assert(&authenticator, &client_data_json, &auth_data, 4);
+            assert_eq!(result, Err(err));
+        }
+    }
+
+    #[test]
+    fn assertion_checks_authenticator_data() {
+        let authenticator = Authenticator::new();
+        let client_data_json = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        for (rp_id, flags, err) in AUTH_DATA_CASES {
+            let auth_data = authenticator.auth_data(rp_id, flags, 5, &[]);
+            let result = assert(&authenticator, &client_data_json, &auth_data, 4);
+            assert_eq!(result, Err(err));
+        }
+    }
+
+    #[test]
+    fn assertion_rejects_counter_going_backwards() {
+        let authenticator = Authenticator::new();
+        let client_data_json = client_data("webauthn.get", CHALLENGE, ORIGIN);
+
+        for count in [5, 4] {
+            let auth_data = authenticator.auth_data("example.com", FLAGS, count, &[]);
+            let result = assert(&authenticator, &client_data_json, &auth_data, 5);
+            assert_eq!(
+                result,
+                Err("The signature counter of the passkey went backwards")
+            );
+        }
+
+        // Passkeys that sync don't count at all
+        let auth_data = authenticator.auth_data("example.com", FLAGS, 0, &[]);
+        assert_eq!(
+            assert(&authenticator, &client_data_json, &auth_data, 0),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn assertion_rejects_bad_signature() {
+        let authenticator = Authenticator::new();
+        let client_data_json = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        let auth_data = authenticator.auth_data("example.com", FLAGS, 5, &[]);
+
+        // Signed over other authenticator data
+        let signed = authenticator.auth_data("example.com", FLAGS, 6, &[]);
+        let signature = authenticator.sign(&signed, &client_data_json);
+        let result = verify_assertion(
+            &rp(),
+            CHALLENGE,
+            &authenticator.public_key(),
+            4,
+            &client_data_json,
+            &auth_data,
+            &signature,
+        );
+        assert_eq!(result, Err("The signature is not valid"));
+
+        // Signed by another key
+        let other = Authenticator {
+            key: SigningKey::from_bytes(&[0x24; 32].into()).unwrap(),
+            credential_id: Vec::new(),
+        };
+        let signature = other.sign(&auth_data, &client_data_json);
+        let result = verify_assertion(
+            &rp(),
+            CHALLENGE,
+            &authenticator.public_key(),
+            4,
+            &client_data_json,
+            &auth_data,
+            &signature,
+        );
+        assert_eq!(result, Err("The signature is not valid"));
+    }
+}